@@ -0,0 +1 @@
+"use strict";const e=document.currentScript?.dataset.url??"/renew",t=1e3*Number(document.currentScript?.dataset.interval??60);async function n(){const t=await fetch(e,{cache:"no-store",credentials:"same-origin"});if(!t.ok)return;const[n,s,a,o]=(await t.text()).split("|"),i=new Worker("/zstatic/worker.js",{type:"module"});i.addEventListener("message",async function(t){i.terminate(),await fetch(e,{method:"POST",credentials:"same-origin",headers:{"Content-Type":"application/x-www-form-urlencoded"},body:new URLSearchParams({solution:`${t.data.nonce}|${n}|${s}|${o}|${a}|${t.data.solveMs}|${t.data.hashes}`})})}),i.postMessage({challenge:n,difficultyBits:s,expiresAt:o})}setInterval(()=>n().catch(()=>{}),t);
\ No newline at end of file
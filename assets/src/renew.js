@@ -0,0 +1,29 @@
+"use strict";
+
+// Include on protected pages to renew the foxyon session in the background:
+// <script src="/zstatic/renew.js" data-url="/renew" data-interval="60"></script>
+// data-url is the renew route configured in [routes].
+const RENEW_URL = document.currentScript?.dataset.url ?? "/renew";
+const interval = Number(document.currentScript?.dataset.interval ?? 60) * 1000;
+
+async function renew() {
+    const response = await fetch(RENEW_URL, {cache: "no-store", credentials: "same-origin"});
+    if (!response.ok) {
+        return;
+    }
+    const [challenge, difficultyBits, integrity_b64, expiresAt] = (await response.text()).split("|");
+    const worker = new Worker("/zstatic/worker.js", {type: "module"});
+
+    worker.addEventListener("message", async function (e) {
+        worker.terminate();
+        await fetch(RENEW_URL, {
+            method: "POST",
+            credentials: "same-origin",
+            headers: {"Content-Type": "application/x-www-form-urlencoded"},
+            body: new URLSearchParams({solution: `${e.data.nonce}|${challenge}|${difficultyBits}|${expiresAt}|${integrity_b64}|${e.data.solveMs}|${e.data.hashes}`}),
+        });
+    });
+    worker.postMessage({challenge, difficultyBits, expiresAt});
+}
+
+setInterval(() => renew().catch(console.error), interval);
@@ -4,6 +4,7 @@ import {blake3} from "https://cdn.jsdelivr.net/npm/@noble/hashes@2.0.0/blake3.js
 
 self.addEventListener("message", async (event) => {
     const {challenge, difficultyBits, expiresAt} = await event.data;
+    const startedAt = performance.now();
     let nonce = 0n;
 
     while (true) {
@@ -15,7 +16,11 @@ self.addEventListener("message", async (event) => {
             trailingZeros++;
         }
         if (trailingZeros >= difficultyBits) {
-            self.postMessage({ nonce: nonce.toString() });
+            self.postMessage({
+                nonce: nonce.toString(),
+                hashes: (nonce + 1n).toString(),
+                solveMs: Math.round(performance.now() - startedAt),
+            });
             return;
         }
         nonce++;
@@ -0,0 +1,27 @@
+use std::hint::black_box;
+use criterion::{Criterion, criterion_group, criterion_main};
+use foxyon::crypto::blake3::{pow_challenge_hash, pow_integrity_hash};
+use foxyon::pow::validate_challenge;
+
+const CHALLENGE: &[u8] = b"aB3dE5gH7jK9";
+const EXPIRES_AT: u64 = 1_700_000_000;
+
+/// The hashes behind every challenge: signing it on issue and checking a submitted nonce.
+fn hashes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("blake3");
+
+    group.bench_function("integrity", |b| {
+        b.iter(|| black_box(pow_integrity_hash(black_box(CHALLENGE), black_box(20), black_box(EXPIRES_AT))));
+    });
+    group.bench_function("challenge", |b| {
+        b.iter(|| black_box(pow_challenge_hash(black_box(b"123456"), black_box(CHALLENGE), black_box(EXPIRES_AT))));
+    });
+    group.bench_function("validate", |b| {
+        b.iter(|| black_box(validate_challenge(black_box(b"123456"), black_box(CHALLENGE), black_box(20), black_box(EXPIRES_AT))));
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, hashes);
+criterion_main!(benches);
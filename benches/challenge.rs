@@ -0,0 +1,30 @@
+use std::hint::black_box;
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use foxyon::challenge_pool::ChallengePool;
+use foxyon::config::CONFIG;
+use foxyon::pow::Challenge;
+use sailfish::TemplateOnce;
+
+/// Serving a challenge page: rendering it on the request versus popping a pre-rendered one.
+fn challenge_page(c: &mut Criterion) {
+    let bits = CONFIG.pow.difficulty.tiers.first().map_or(20, |tier| tier.bits);
+    let mut group = c.benchmark_group("challenge_page");
+
+    group.bench_function("render", |b| {
+        b.iter(|| black_box(Challenge::with_bits(black_box(bits)).render_once()));
+    });
+
+    let pool = ChallengePool::new();
+    group.bench_function("pool_pop", |b| {
+        b.iter_batched(
+            || pool.refill(bits, 1),
+            |_| black_box(pool.pop(black_box(bits))),
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, challenge_page);
+criterion_main!(benches);
@@ -0,0 +1,210 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::load::to_f64;
+use tracing::{error, warn};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Resources with a `<resource>.pressure` file in every cgroup v2 directory.
+const PSI_RESOURCES: [&str; 3] = ["cpu", "memory", "io"];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct CpuStat {
+    usage_usec: u64,
+    nr_periods: u64,
+    nr_throttled: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgroupSample {
+    /// CPU usage as a percentage of the `cpu.max` quota (or of every CPU when unlimited).
+    pub cpu: f64,
+    /// Percentage of enforcement periods in which the cgroup was throttled.
+    pub throttled: f64,
+}
+
+/// Reads `cpu.stat` and `cpu.max` of a cgroup v2 directory.
+pub struct CgroupCpu {
+    dir: PathBuf,
+    last: CpuStat,
+}
+
+impl CgroupCpu {
+    /// Opens the cgroup at `path`, or the cgroup of this process when `path` is empty.
+    ///
+    /// Returns `None` if `cpu.stat` can not be read, e.g. on a cgroup v1 host.
+    #[must_use]
+    pub fn new(path: &str) -> Option<Self> {
+        let dir = cgroup_dir(path)?;
+        let last = match read_cpu_stat(&dir) {
+            Some(stat) => stat,
+            None => {
+                error!(path = %dir.display(), "Unable to read cgroup v2 cpu.stat");
+                return None;
+            }
+        };
+        Some(Self { dir, last })
+    }
+
+    pub fn sample(&mut self, elapsed: Duration) -> Option<CgroupSample> {
+        let stat = read_cpu_stat(&self.dir)?;
+        let cpus = fs::read_to_string(self.dir.join("cpu.max")).ok()
+            .and_then(|s| parse_cpu_max(&s))
+            .unwrap_or_else(available_cpus);
+
+        let usage = to_f64(stat.usage_usec.saturating_sub(self.last.usage_usec));
+        let periods = stat.nr_periods.saturating_sub(self.last.nr_periods);
+        let throttled = to_f64(stat.nr_throttled.saturating_sub(self.last.nr_throttled));
+        self.last = stat;
+
+        let budget = elapsed.as_secs_f64() * 1_000_000.0 * cpus;
+        Some(CgroupSample {
+            cpu: if budget > 0.0 { usage / budget * 100.0 } else { 0.0 },
+            throttled: if periods > 0 { throttled / to_f64(periods) * 100.0 } else { 0.0 },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PsiSample {
+    pub cpu: f64,
+    pub memory: f64,
+    pub io: f64,
+}
+
+/// Reads the `some avg10` stall percentage from the `cpu.pressure`, `memory.pressure` and
+/// `io.pressure` files of a cgroup v2 directory, the stalls of our own slice.
+pub struct Psi {
+    dir: PathBuf,
+    /// Resources whose file could not be read on the last sample, so it is only reported once.
+    missing: [bool; PSI_RESOURCES.len()],
+}
+
+impl Psi {
+    /// Opens the cgroup at `path`, or the cgroup of this process when `path` is empty.
+    #[must_use]
+    pub fn new(path: &str) -> Option<Self> {
+        Some(Self { dir: cgroup_dir(path)?, missing: [false; PSI_RESOURCES.len()] })
+    }
+
+    /// Stalls of every resource, `0.0` for the ones whose file can not be read.
+    pub fn sample(&mut self) -> PsiSample {
+        let mut stalls = [0.0; PSI_RESOURCES.len()];
+        for ((stall, resource), missing) in stalls.iter_mut().zip(PSI_RESOURCES).zip(&mut self.missing) {
+            let path = self.dir.join(format!("{resource}.pressure"));
+            match fs::read_to_string(&path) {
+                Ok(s) => {
+                    *stall = parse_psi_some_avg10(&s).unwrap_or(0.0);
+                    *missing = false;
+                }
+                Err(e) => {
+                    if !*missing {
+                        warn!(error = ?e, path = %path.display(), "Unable to read pressure stall information, counting no stall");
+                    }
+                    *missing = true;
+                }
+            }
+        }
+        let [cpu, memory, io] = stalls;
+        PsiSample { cpu, memory, io }
+    }
+}
+
+/// The cgroup directory at `path`, or the one of this process when `path` is empty.
+fn cgroup_dir(path: &str) -> Option<PathBuf> {
+    if path.is_empty() { own_cgroup() } else { Some(PathBuf::from(path)) }
+}
+
+fn own_cgroup() -> Option<PathBuf> {
+    let cgroup = match fs::read_to_string("/proc/self/cgroup") {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error = ?e, "Unable to read /proc/self/cgroup");
+            return None;
+        }
+    };
+    // The unified hierarchy is the `0::<path>` entry.
+    let relative = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_ROOT).join(relative.trim_start_matches('/')))
+}
+
+fn available_cpus() -> f64 {
+    std::thread::available_parallelism()
+        .map_or(1.0, |n| f64::from(u32::try_from(n.get()).unwrap_or(u32::MAX)))
+}
+
+fn read_cpu_stat(dir: &Path) -> Option<CpuStat> {
+    fs::read_to_string(dir.join("cpu.stat")).ok().map(|s| parse_cpu_stat(&s))
+}
+
+fn parse_cpu_stat(s: &str) -> CpuStat {
+    let mut stat = CpuStat::default();
+    for line in s.lines() {
+        let Some((key, value)) = line.split_once(' ') else { continue };
+        let Ok(value) = value.trim().parse::<u64>() else { continue };
+        match key {
+            "usage_usec" => stat.usage_usec = value,
+            "nr_periods" => stat.nr_periods = value,
+            "nr_throttled" => stat.nr_throttled = value,
+            _ => {}
+        }
+    }
+    stat
+}
+
+/// Parses `cpu.max` (`"<quota> <period>"` or `"max <period>"`) into a number of CPUs.
+fn parse_cpu_max(s: &str) -> Option<f64> {
+    let (quota, period) = s.trim().split_once(' ')?;
+    let quota = quota.parse::<u32>().ok()?;
+    let period = period.parse::<u32>().ok().filter(|p| *p > 0)?;
+    Some(f64::from(quota) / f64::from(period))
+}
+
+fn parse_psi_some_avg10(s: &str) -> Option<f64> {
+    s.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split(' ')
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse::<f64>().ok()
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn cpu_max_limits_cpus(){
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(1.5));
+        assert_eq!(parse_cpu_max("max 100000\n"), None, "An unlimited quota should fall back to every CPU");
+    }
+
+    #[test]
+    fn cpu_stat_is_parsed(){
+        let stat = parse_cpu_stat("usage_usec 2500\nuser_usec 2000\nsystem_usec 500\nnr_periods 10\nnr_throttled 4\nthrottled_usec 900\n");
+        assert_eq!(stat.usage_usec, 2500);
+        assert_eq!(stat.nr_periods, 10);
+        assert_eq!(stat.nr_throttled, 4);
+    }
+
+    #[test]
+    fn psi_reads_the_pressure_of_the_cgroup(){
+        let dir = std::env::temp_dir().join(format!("foxyon-psi-{}", std::process::id()));
+        assert!(fs::create_dir_all(&dir).is_ok());
+        assert!(fs::write(dir.join("cpu.pressure"), "some avg10=7.25 avg60=1.00 avg300=0.00 total=1\n").is_ok());
+        assert!(fs::write(dir.join("io.pressure"), "some avg10=3.00 avg60=1.00 avg300=0.00 total=1\n").is_ok());
+
+        let psi = Psi::new(&dir.to_string_lossy());
+        let sample = psi.map(|mut psi| (psi.sample(), psi.missing));
+        assert!(sample.is_some_and(|(sample, missing)| (sample.cpu - 7.25).abs() < f64::EPSILON
+            && sample.memory.abs() < f64::EPSILON
+            && (sample.io - 3.0).abs() < f64::EPSILON
+            && missing == [false, true, false]), "A missing file should count as no stall and be remembered");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn psi_reads_some_avg10(){
+        let psi = "some avg10=12.50 avg60=3.00 avg300=1.00 total=1234\nfull avg10=4.00 avg60=1.00 avg300=0.00 total=99\n";
+        assert_eq!(parse_psi_some_avg10(psi), Some(12.5));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio::sync::watch::Receiver;
+use tokio::time::{sleep, Duration, Instant};
+use crate::config::{CONFIG, DifficultyMode};
+use crate::pow::Challenge;
+use actix_web::web::Bytes;
+use crossbeam_queue::ArrayQueue;
+use sailfish::TemplateOnce;
+use tracing::error;
+#[cfg(feature = "debug")]
+use tracing::debug;
+
+/// A challenge page rendered ahead of time.
+pub struct PooledPage {
+    pub body: Bytes,
+    pub expires_at: u64,
+    rendered_at: Instant,
+}
+
+/// Lock-free rings of pre-rendered challenge pages, one per difficulty the ladder can select.
+///
+/// Only the ring of the current difficulty is filled by [`challenge_producer`], so serving a
+/// challenge under a flood is a queue pop instead of randomness, a MAC and a template render.
+pub struct ChallengePool {
+    rings: Vec<(u8, ArrayQueue<PooledPage>)>,
+    max_age: Duration,
+}
+
+impl ChallengePool {
+    #[must_use]
+    pub fn new() -> Self {
+        let mut bits: BTreeSet<u8> = CONFIG.pow.difficulty.tiers.iter().map(|tier| tier.bits).collect();
+        if CONFIG.pow.difficulty.mode == DifficultyMode::TargetSolveTime {
+            bits.extend(CONFIG.pow.telemetry.min_bits..=CONFIG.pow.telemetry.max_bits);
+        }
+        let size = CONFIG.pow.pool.size.max(1);
+        Self {
+            rings: bits.into_iter().map(|bits| (bits, ArrayQueue::new(size))).collect(),
+            max_age: Duration::from_millis(CONFIG.pow.pool.max_age_ms),
+        }
+    }
+
+    /// Takes a page of `difficulty_bits`, skipping the ones older than `max_age`.
+    ///
+    /// Returns `None` if the ring is empty or there is no ring for that difficulty.
+    #[must_use]
+    pub fn pop(&self, difficulty_bits: u8) -> Option<PooledPage> {
+        let ring = self.ring(difficulty_bits)?;
+        while let Some(page) = ring.pop() {
+            if page.rendered_at.elapsed() <= self.max_age {
+                return Some(page);
+            }
+        }
+        None
+    }
+
+    /// Renders up to `max` pages of `difficulty_bits` into its ring, returning how many were added.
+    pub fn refill(&self, difficulty_bits: u8, max: usize) -> usize {
+        let Some(ring) = self.ring(difficulty_bits) else {
+            return 0;
+        };
+        let mut added: usize = 0;
+        while added < max && !ring.is_full() {
+            let Some(page) = render(difficulty_bits) else {
+                break;
+            };
+            if ring.push(page).is_err() {
+                break;
+            }
+            added = added.saturating_add(1);
+        }
+        added
+    }
+
+    /// Drops the stale pages at the front of the ring of `difficulty_bits`.
+    fn prune(&self, difficulty_bits: u8) {
+        if let Some(page) = self.pop(difficulty_bits)
+            && let Some(ring) = self.ring(difficulty_bits)
+        {
+            // The first fresh page goes to the back; the pages behind it are newer anyway.
+            let _ = ring.push(page);
+        }
+    }
+
+    /// Empties every ring except the one of `difficulty_bits`.
+    fn invalidate_except(&self, difficulty_bits: u8) {
+        for (bits, ring) in &self.rings {
+            if *bits != difficulty_bits {
+                while ring.pop().is_some() {}
+            }
+        }
+    }
+
+    #[inline]
+    fn ring(&self, difficulty_bits: u8) -> Option<&ArrayQueue<PooledPage>> {
+        self.rings.iter().find(|(bits, _)| *bits == difficulty_bits).map(|(_, ring)| ring)
+    }
+}
+
+impl Default for ChallengePool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn render(difficulty_bits: u8) -> Option<PooledPage> {
+    let challenge = Challenge::with_bits(difficulty_bits);
+    let expires_at = challenge.expires_at;
+    match challenge.render_once() {
+        Ok(body) => Some(PooledPage { body: Bytes::from(body), expires_at, rendered_at: Instant::now() }),
+        Err(e) => {
+            error!(error = ?e, "Failed to render pooled challenge");
+            None
+        }
+    }
+}
+
+/// Keeps the ring of the current difficulty full, emptying the others when it changes.
+///
+/// Pages are rendered in batches of `batch`, yielding to other tasks between pages so the pool
+/// only uses CPU the request handlers leave idle.
+pub async fn challenge_producer(pool: Arc<ChallengePool>, mut difficulty: Receiver<u8>) {
+    let cfg = &CONFIG.pow.pool;
+    let mut current = *difficulty.borrow_and_update();
+
+    loop {
+        pool.prune(current);
+        let mut added: usize = 0;
+        while added < cfg.batch {
+            if pool.refill(current, 1) == 0 {
+                break;
+            }
+            added = added.saturating_add(1);
+            tokio::task::yield_now().await;
+        }
+
+        #[cfg(feature = "debug")]
+        if added > 0 {
+            debug!(difficulty_bits = current, added, "Challenge pool refilled");
+        }
+
+        tokio::select! {
+            changed = difficulty.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let bits = *difficulty.borrow_and_update();
+                if bits != current {
+                    pool.invalidate_except(bits);
+                    current = bits;
+                }
+            }
+            () = sleep(Duration::from_millis(cfg.refill_interval_ms)) => {}
+        }
+    }
+}
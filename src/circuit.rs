@@ -0,0 +1,104 @@
+use std::net::Ipv6Addr;
+use std::sync::LazyLock;
+use crate::config::CONFIG;
+use crate::identity::{ClientId, Network};
+use tracing::error;
+
+/// `fc00::/7`, the unique local range Tor exports circuit IDs in.
+const UNIQUE_LOCAL: Network = Network { address: 0xfc00_0000_0000_0000_0000_0000_0000_0000, len: 7 };
+/// Prefix of the addresses exported by `HiddenServiceExportCircuitID`.
+const TOR_EXPORT_PREFIX: u128 = 0xfc00_dead_beef_4dad_0000_0000_0000_0000;
+/// Everything but the low 32 bits, which hold Tor's own circuit ID.
+const PREFIX_MASK: u128 = !0xffff_ffff;
+/// Index of the address group replaced by the instance tag.
+const INSTANCE_GROUP: usize = 3;
+
+static NETWORK: LazyLock<Network> = LazyLock::new(|| {
+    Network::parse(&CONFIG.circuit.network).unwrap_or_else(|| {
+        error!(network = %CONFIG.circuit.network, "Invalid circuit network, falling back to fc00::/7");
+        UNIQUE_LOCAL
+    })
+});
+
+static CONTROL_PREFIX: LazyLock<u128> = LazyLock::new(|| {
+    match CONFIG.circuit.control_prefix.parse::<Ipv6Addr>() {
+        Ok(prefix) => u128::from(prefix) & PREFIX_MASK,
+        Err(e) => {
+            error!(error = ?e, prefix = %CONFIG.circuit.control_prefix, "Invalid control prefix, falling back to fc00:dead:beef:4dad::");
+            TOR_EXPORT_PREFIX
+        }
+    }
+});
+
+/// Tor circuits as client identities: the whole `X-Circuit-Id` address.
+///
+/// Tor only puts its circuit ID in the low 32 bits; keeping the rest lets several Tor daemons
+/// or onion services feed one foxyon without sharing sessions, as long as each one is given its
+/// own prefix or instance tag.
+impl ClientId {
+    /// Parses an `X-Circuit-Id` address, with the fourth group replaced by `instance` if given.
+    ///
+    /// Returns `None` if it is not an IPv6 address or falls outside the configured network.
+    #[must_use]
+    pub fn from_circuit(address: &str, instance: Option<u16>) -> Option<Self> {
+        Self::from_circuit_addr(address.parse().ok()?, instance)
+    }
+
+    /// Like [`ClientId::from_circuit`], for an address that is already parsed.
+    #[must_use]
+    pub fn from_circuit_addr(address: Ipv6Addr, instance: Option<u16>) -> Option<Self> {
+        if !NETWORK.contains(u128::from(address)) {
+            return None;
+        }
+        let Some(instance) = instance else {
+            return Some(Self(u128::from(address)));
+        };
+        let mut segments = address.segments();
+        if let Some(group) = segments.get_mut(INSTANCE_GROUP) {
+            *group = instance;
+        }
+        Some(Self(u128::from(Ipv6Addr::from(segments))))
+    }
+
+    /// Circuit `circuit_id` of the Tor daemon whose control port is configured.
+    #[must_use]
+    pub fn from_control_port(circuit_id: u32) -> Self {
+        Self(*CONTROL_PREFIX | u128::from(circuit_id))
+    }
+
+    /// Tor's circuit ID, if this circuit belongs to the Tor daemon whose control port is configured.
+    #[must_use]
+    pub fn on_control_port(self) -> Option<u32> {
+        (self.0 & PREFIX_MASK == *CONTROL_PREFIX).then(|| self.circuit())
+    }
+
+    /// The low 32 bits, the circuit ID within its Tor daemon.
+    #[must_use]
+    pub fn circuit(self) -> u32 {
+        let [.., a, b, c, d] = self.0.to_be_bytes();
+        u32::from_be_bytes([a, b, c, d])
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn unique_local_network(){
+        assert!(UNIQUE_LOCAL.contains(u128::from(Ipv6Addr::new(0xfc00, 0xdead, 0xbeef, 0x4dad, 0, 0, 0, 0x12d))));
+        assert!(UNIQUE_LOCAL.contains(u128::from(Ipv6Addr::new(0xfd12, 0, 0, 0, 0, 0, 0, 1))));
+        assert!(!UNIQUE_LOCAL.contains(u128::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))), "Global addresses are not circuits");
+    }
+
+    #[test]
+    fn circuits_keep_their_instance(){
+        let first = ClientId::from_circuit("fc00:dead:beef:4dad::12d", None);
+        let second = ClientId::from_circuit("fc00:dead:beef:4dad::12d", Some(2));
+        assert!(first.is_some_and(|id| id.circuit() == 0x12d));
+        assert!(second.is_some_and(|id| id.circuit() == 0x12d && id.to_string() == "fc00:dead:beef:2::12d"));
+        assert_ne!(first, second, "The same circuit ID on two instances should be two identities");
+        assert_eq!(ClientId::from_circuit("2001:db8::12d", None), None);
+        assert_eq!(ClientId::from_circuit("10.0.0.1", None), None);
+    }
+}
@@ -17,6 +17,15 @@ pub struct Config {
     pub session: Session,
     pub security: Security,
     pub system: System,
+    pub upstream: Upstream,
+    pub proxy: Proxy,
+    pub forward_auth: ForwardAuth,
+    pub renewal: Renewal,
+    pub ban: Ban,
+    pub tarpit: Tarpit,
+    pub tor: Tor,
+    pub circuit: Circuit,
+    pub identity: Identity,
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,35 +36,105 @@ pub struct Server {
     pub backlog: u32,
     pub max_connections: usize,
     pub keep_alive: u64,
+    pub proxy_protocol: ProxyProtocol,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyProtocol {
+    pub enabled: bool,
+    pub trusted_sources: Vec<String>,
+    pub timeout_ms: u64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Pow {
     pub challenge_ttl: u64,
+    pub max_challenge_ttl: u64,
+    pub reference_hashrate: f64,
+    pub safety_factor: f64,
     pub difficulty: Difficulty,
-    pub cpu_thresholds: CpuThresholds
+    pub telemetry: Telemetry,
+    pub circuit_penalty: CircuitPenalty,
+    pub issuance: Issuance,
+    pub pool: Pool,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Difficulty {
-    pub minimum: u8,
-    pub medium: u8,
-    pub high: u8,
-    pub ultra: u8
+    pub mode: DifficultyMode,
+    pub smoothing: f32,
+    pub hysteresis: f32,
+    pub tiers: Vec<Tier>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct Tier {
+    pub cpu_above: f32,
+    pub bits: u8,
+    pub target_solve_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DifficultyMode {
+    Load,
+    TargetSolveTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CircuitPenalty {
+    pub enabled: bool,
+    pub max_capacity: u64,
+    pub window: u64,
+    pub half_life: u64,
+    pub points_per_bit: f64,
+    pub max_extra_bits: u8,
+    pub unsolved_weight: f64,
+    pub failed_weight: f64,
+    pub replay_weight: f64,
+    pub session_weight: f64,
+    pub free_sessions: f64,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct CpuThresholds {
-    pub low: f32,
-    pub medium: f32,
-    pub high: f32,
-    pub critical: f32,
+pub struct Issuance {
+    pub enabled: bool,
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+    pub on_limit: OnLimit,
+    pub cache_capacity: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Pool {
+    pub enabled: bool,
+    pub size: usize,
+    pub max_age_ms: u64,
+    pub batch: usize,
+    pub refill_interval_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnLimit {
+    Reject,
+    Reuse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Telemetry {
+    pub window: usize,
+    pub min_samples: usize,
+    pub min_bits: u8,
+    pub max_bits: u8,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Routes {
     pub auth: String,
     pub challenge: String,
+    pub renew: String,
+    pub kill: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -70,14 +149,227 @@ pub struct Session {
     pub max_capacity: u64,
     pub tti: u64,
     pub ttl: u64,
+    pub max_tti: u64,
+    pub max_ttl: u64,
+    pub effort_base_bits: u8,
+    pub effort_scale: f64,
+    pub credits: Credits,
+    pub cookie: SessionCookie,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionCookie {
+    pub enabled: bool,
+    pub name: String,
+    pub secure: bool,
+    pub same_site: CookieSameSite,
+    pub deny_capacity: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credits {
+    pub enabled: bool,
+    pub capacity: f64,
+    pub refill_per_sec: f64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Security {
     pub keyed_hash: String,
+    pub kill_token: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct System {
     pub cpu_usage_update_interval: u64,
+    pub cpu_source: CpuSource,
+    pub cgroup_path: String,
+    pub psi: bool,
+    pub load: LoadWeights,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuSource {
+    Host,
+    Cgroup,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoadWeights {
+    pub cpu: LoadInput,
+    pub challenge_rate: LoadInput,
+    pub auth_rate: LoadInput,
+    pub auth_reject_ratio: LoadInput,
+    pub in_flight: LoadInput,
+    pub latency: LoadInput,
+    pub throttled: LoadInput,
+    pub psi_cpu: LoadInput,
+    pub psi_memory: LoadInput,
+    pub psi_io: LoadInput,
+    pub upstream_latency: LoadInput,
+    pub upstream_errors: LoadInput,
+    pub upstream_active: LoadInput,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct LoadInput {
+    pub weight: f64,
+    pub saturation: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Upstream {
+    pub enabled: bool,
+    pub mode: ProbeMode,
+    pub url: String,
+    pub unix_socket: String,
+    pub interval: u64,
+    pub timeout_ms: u64,
+    pub window: usize,
+    pub lockdown: bool,
+    pub lockdown_after: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Proxy {
+    pub enabled: bool,
+    pub url: String,
+    pub unix_socket: String,
+    pub connect_timeout_ms: u64,
+    pub response_timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardAuth {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeMode {
+    Health,
+    StubStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Renewal {
+    pub enabled: bool,
+    pub bits_discount: u8,
+    pub min_bits: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Ban {
+    pub enabled: bool,
+    pub max_failures: u32,
+    pub window: u64,
+    pub duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tarpit {
+    pub enabled: bool,
+    pub max_connections: usize,
+    pub interval_ms: u64,
+    pub duration: u64,
+    pub reasons: TarpitReasons,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Circuit {
+    pub header: String,
+    pub network: String,
+    pub instance_header: String,
+    pub control_prefix: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tor {
+    pub enabled: bool,
+    pub address: String,
+    pub unix_socket: String,
+    pub auth: TorAuth,
+    pub cookie_path: String,
+    pub password: String,
+    pub timeout_ms: u64,
+    pub close_on_ban: bool,
+    pub circuit_events: bool,
+    pub reconnect_interval: u64,
+    pub fallback_max_ttl: u64,
+    pub fallback_max_tti: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TorAuth {
+    None,
+    Cookie,
+    Password,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Identity {
+    pub mode: IdentityMode,
+    pub forwarded: Forwarded,
+    pub cookie: IdentityCookie,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityMode {
+    Tor,
+    Forwarded,
+    Cookie,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Forwarded {
+    pub header: ForwardedHeader,
+    pub trusted_proxies: Vec<String>,
+    pub aggregation: Aggregation,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Aggregation {
+    pub enabled: bool,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub max_failures: u32,
+    pub issuance_capacity: f64,
+    pub issuance_refill_per_sec: f64,
+    pub credits_capacity: f64,
+    pub credits_refill_per_sec: f64,
+    pub points_per_bit: f64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    #[serde(rename = "X-Forwarded-For")]
+    XForwardedFor,
+    #[serde(rename = "X-Real-IP")]
+    XRealIp,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdentityCookie {
+    pub name: String,
+    pub max_age: u64,
+    pub secure: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TarpitReasons {
+    pub banned: bool,
+    pub rate_limited: bool,
+    pub replayed: bool,
+    pub malformed: bool,
+    pub failed: bool,
 }
\ No newline at end of file
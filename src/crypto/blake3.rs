@@ -29,6 +29,53 @@ pub fn pow_integrity_hash(challenge: &[u8], difficulty: u8, timestamp: u64) -> [
     hasher.finalize().into()
 }
 
+/// Integrity hash of a renewal challenge, bound to the circuit it was issued to.
+///
+/// The extra domain and circuit address make it impossible to redeem a renewal challenge
+/// through the regular challenge endpoint, or from another circuit.
+#[must_use]
+pub fn pow_renewal_hash(challenge: &[u8], difficulty: u8, timestamp: u64, circuit: &[u8; 16]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(&BLAKE_KEY);
+    hasher.update(challenge);
+    hasher.update(&[difficulty]);
+    hasher.update(&timestamp.to_le_bytes());
+    hasher.update(b"renew");
+    hasher.update(circuit);
+    hasher.finalize().into()
+}
+
+/// Integrity hash of a challenge made harder by `penalty` bits of its circuit's history.
+///
+/// Signing the penalty lets the solution be redeemed for a session at the base difficulty.
+#[must_use]
+pub fn pow_penalized_hash(challenge: &[u8], difficulty: u8, timestamp: u64, penalty: u8) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(&BLAKE_KEY);
+    hasher.update(challenge);
+    hasher.update(&[difficulty]);
+    hasher.update(&timestamp.to_le_bytes());
+    hasher.update(b"penalty");
+    hasher.update(&[penalty]);
+    hasher.finalize().into()
+}
+
+/// MAC of the client ID carried by an identity cookie.
+#[must_use]
+pub fn identity_cookie_mac(client_id: &[u8; 16]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(&BLAKE_KEY);
+    hasher.update(b"identity");
+    hasher.update(client_id);
+    hasher.finalize().into()
+}
+
+/// MAC of the payload of a session token.
+#[must_use]
+pub fn session_token_mac(payload: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(&BLAKE_KEY);
+    hasher.update(b"session");
+    hasher.update(payload);
+    hasher.finalize().into()
+}
+
 #[must_use]
 pub fn pow_challenge_hash(nonce: &[u8], challenge: &[u8], timestamp: u64) -> [u8; 32] {
     let mut hasher = blake3::Hasher::new();
@@ -67,4 +114,26 @@ mod tests {
         let diff = pow_integrity_hash(&challenge, difficulty, 14_57_30_33_29);
         assert_ne!(base, diff, "A different timestamp should generate a different output.");
     }
+
+    #[test]
+    fn renewal_hash_is_bound_to_circuit(){
+        let challenge: [u8;4] = *b"test";
+        let difficulty: u8 = 69;
+        let timestamp: u64 = 17_57_30_33_29;
+        let base = pow_renewal_hash(&challenge, difficulty, timestamp, &[1; 16]);
+
+        assert_ne!(base, pow_integrity_hash(&challenge, difficulty, timestamp), "A renewal challenge should not pass as a regular one");
+        assert_ne!(base, pow_renewal_hash(&challenge, difficulty, timestamp, &[2; 16]), "A different circuit should generate a different output");
+    }
+
+    #[test]
+    fn penalized_hash_is_bound_to_penalty(){
+        let challenge: [u8;4] = *b"test";
+        let difficulty: u8 = 69;
+        let timestamp: u64 = 17_57_30_33_29;
+        let base = pow_penalized_hash(&challenge, difficulty, timestamp, 2);
+
+        assert_ne!(base, pow_integrity_hash(&challenge, difficulty, timestamp), "A penalized challenge should not pass as a regular one");
+        assert_ne!(base, pow_penalized_hash(&challenge, difficulty, timestamp, 1), "A different penalty should generate a different output");
+    }
 }
\ No newline at end of file
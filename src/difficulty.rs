@@ -0,0 +1,114 @@
+use crate::config::Tier;
+
+/// Exponentially weighted moving average of a load signal.
+///
+/// `alpha` is the weight of the newest sample: `1.0` disables smoothing,
+/// values closer to `0.0` react more slowly to spikes.
+pub struct Ewma {
+    alpha: f32,
+    value: Option<f32>,
+}
+
+impl Ewma {
+    #[must_use]
+    pub fn new(alpha: f32) -> Self {
+        Self { alpha: alpha.clamp(f32::EPSILON, 1.0), value: None }
+    }
+
+    pub fn update(&mut self, sample: f32) -> f32 {
+        let value = match self.value {
+            Some(previous) => self.alpha * sample + (1.0 - self.alpha) * previous,
+            None => sample,
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+/// Ordered list of difficulty tiers with hysteresis between them.
+///
+/// A tier is entered once the load reaches its `cpu_above` threshold and is only
+/// left again when the load drops `hysteresis` below that threshold, so a signal
+/// hovering around a boundary does not flap between two difficulties.
+pub struct Ladder {
+    tiers: Vec<Tier>,
+    hysteresis: f32,
+    current: usize,
+}
+
+impl Ladder {
+    /// # Panics
+    /// Will panic if `tiers` is empty, the configuration is unusable without at least one tier.
+    #[must_use]
+    pub fn new(tiers: &[Tier], hysteresis: f32) -> Self {
+        assert!(!tiers.is_empty(), "pow.difficulty.tiers must contain at least one tier");
+        let mut tiers = tiers.to_vec();
+        tiers.sort_by(|a, b| a.cpu_above.total_cmp(&b.cpu_above));
+        Self { tiers, hysteresis: hysteresis.max(0.0), current: 0 }
+    }
+
+    #[must_use]
+    pub fn bits(&self) -> u8 {
+        self.tiers.get(self.current).map_or(0, |tier| tier.bits)
+    }
+
+    #[must_use]
+    pub fn target_solve_ms(&self) -> Option<u64> {
+        self.tiers.get(self.current).and_then(|tier| tier.target_solve_ms)
+    }
+
+    pub fn select(&mut self, load: f32) -> u8 {
+        while let Some(next) = self.current.checked_add(1).and_then(|i| self.tiers.get(i)) {
+            if load < next.cpu_above {
+                break;
+            }
+            self.current = self.current.saturating_add(1);
+        }
+        while self.current > 0 {
+            let Some(tier) = self.tiers.get(self.current) else { break };
+            if load >= tier.cpu_above - self.hysteresis {
+                break;
+            }
+            self.current = self.current.saturating_sub(1);
+        }
+        self.bits()
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn ladder_uses_every_tier(){
+        let tiers = [
+            Tier { cpu_above: 90.0, bits: 26, target_solve_ms: None },
+            Tier { cpu_above: 0.0, bits: 17, target_solve_ms: None },
+            Tier { cpu_above: 30.0, bits: 20, target_solve_ms: None },
+        ];
+        let mut ladder = Ladder::new(&tiers, 0.0);
+
+        assert_eq!(ladder.select(10.0), 17);
+        assert_eq!(ladder.select(45.0), 20);
+        assert_eq!(ladder.select(95.0), 26, "The highest tier should be reachable");
+        assert_eq!(ladder.select(5.0), 17);
+    }
+
+    #[test]
+    fn ladder_hysteresis_prevents_flapping(){
+        let tiers = [Tier { cpu_above: 0.0, bits: 17, target_solve_ms: None }, Tier { cpu_above: 30.0, bits: 20, target_solve_ms: None }];
+        let mut ladder = Ladder::new(&tiers, 5.0);
+
+        assert_eq!(ladder.select(31.0), 20);
+        assert_eq!(ladder.select(29.0), 20, "Dropping just below the threshold should keep the tier");
+        assert_eq!(ladder.select(24.0), 17, "Dropping below threshold minus hysteresis should leave the tier");
+    }
+
+    #[test]
+    fn ewma_smooths_spikes(){
+        let mut ewma = Ewma::new(0.5);
+
+        assert!((ewma.update(10.0) - 10.0).abs() < f32::EPSILON, "The first sample should be taken as is");
+        assert!((ewma.update(90.0) - 50.0).abs() < f32::EPSILON);
+    }
+}
@@ -0,0 +1,488 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use crate::config::{CONFIG, Aggregation, ForwardedHeader, IdentityMode};
+use crate::crypto::blake3::identity_cookie_mac;
+use actix_web::{HttpRequest, error};
+use actix_web::cookie::{Cookie, SameSite, time::Duration};
+use actix_web::http::header::HeaderMap;
+use base64_simd::{URL_SAFE_NO_PAD, Out};
+use rand::Rng;
+use subtle::ConstantTimeEq;
+use tracing::error;
+
+/// `::ffff:0:0/96`, the IPv4-mapped addresses.
+const IPV4_MAPPED: Network = Network { address: 0xffff_0000_0000, len: 96 };
+
+/// Bytes of the MAC kept in an identity cookie, after the 16 bytes of the client ID.
+const COOKIE_MAC_LEN: usize = 16;
+const COOKIE_LEN: usize = 16 + COOKIE_MAC_LEN;
+
+/// Who a session, ban or limit belongs to.
+///
+/// 128 bits hold any identity a [`ClientIdentity`] extracts: a Tor circuit address, a client
+/// IP with IPv4 mapped into IPv6, or the random ID of a signed cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId(pub(crate) u128);
+
+impl ClientId {
+    /// `::`, which no identity resolves to, shared by the requests whose identity is unknown
+    /// so they are limited together instead of not at all. The challenge pages handing out a new
+    /// identity cookie are limited under it too.
+    pub const UNIDENTIFIED: ClientId = ClientId(0);
+
+    #[must_use]
+    pub fn from_ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self(u128::from(ip.to_ipv6_mapped())),
+            IpAddr::V6(ip) => Self(u128::from(ip)),
+        }
+    }
+
+    #[must_use]
+    pub fn to_bytes(self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+
+    /// Network this client is aggregated into by `[identity.forwarded.aggregation]`.
+    ///
+    /// `None` unless aggregation is enabled in `forwarded` mode, so circuit and cookie
+    /// identities, which are not addresses, are never grouped.
+    #[must_use]
+    pub fn prefix(self) -> Option<Network> {
+        self.prefix_in(CONFIG.identity.mode, &CONFIG.identity.forwarded.aggregation)
+    }
+
+    /// [`prefix`](Self::prefix) under an explicit identity `mode` and aggregation `cfg`.
+    fn prefix_in(self, mode: IdentityMode, cfg: &Aggregation) -> Option<Network> {
+        if mode != IdentityMode::Forwarded || !cfg.enabled {
+            return None;
+        }
+        let len = if IPV4_MAPPED.contains(self.0) {
+            cfg.ipv4_prefix.min(32).saturating_add(96)
+        } else {
+            cfg.ipv6_prefix.min(128)
+        };
+        Some(Network { address: self.0 & Network::mask(len), len })
+    }
+
+    /// Keys the limits of this client are counted under, its own and its prefix's if aggregated.
+    pub fn limit_keys(self) -> impl Iterator<Item = LimitKey> {
+        std::iter::once(LimitKey::Client(self)).chain(self.prefix().map(LimitKey::Prefix))
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Ipv6Addr::from(self.0).fmt(f)
+    }
+}
+
+/// An `address/len` network, IPv4 networks are mapped into IPv6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Network {
+    pub(crate) address: u128,
+    pub(crate) len: u8,
+}
+
+impl Network {
+    /// Parses `address/len`.
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let (address, len) = value.split_once('/')?;
+        let len: u8 = len.parse().ok()?;
+        match address.parse::<IpAddr>().ok()? {
+            IpAddr::V4(ip) if len <= 32 => Some(Self { address: u128::from(ip.to_ipv6_mapped()), len: len.saturating_add(96) }),
+            IpAddr::V6(ip) if len <= 128 => Some(Self { address: u128::from(ip), len }),
+            _ => None,
+        }
+    }
+
+    /// Parses every network of a config list, logging and skipping the invalid ones.
+    pub(crate) fn parse_all(values: &[String]) -> Vec<Self> {
+        values.iter()
+            .filter_map(|value| {
+                let network = Self::parse(value);
+                if network.is_none() {
+                    error!(network = %value, "Invalid network, ignoring it");
+                }
+                network
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub(crate) fn contains(self, address: u128) -> bool {
+        (address ^ self.address) & Self::mask(self.len) == 0
+    }
+
+    #[inline]
+    fn mask(len: u8) -> u128 {
+        u128::MAX.checked_shl(128u32.saturating_sub(u32::from(len))).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", Ipv6Addr::from(self.address), self.len)
+    }
+}
+
+/// What a limit, ban or difficulty penalty is counted against: one client, or every client of
+/// the network prefix it is aggregated into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitKey {
+    Client(ClientId),
+    Prefix(Network),
+}
+
+impl fmt::Display for LimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKey::Client(client_id) => client_id.fmt(f),
+            LimitKey::Prefix(network) => network.fmt(f),
+        }
+    }
+}
+
+/// Tells clients apart, `auth` and the challenge routes key everything by what it returns.
+pub trait ClientIdentity {
+    /// Identity of the client sending `req`.
+    ///
+    /// # Errors
+    /// Will return `Err` if the request does not carry a valid identity.
+    fn identify(&self, req: &HttpRequest) -> Result<ClientId, actix_web::Error>;
+
+    /// Identity of the client fetching a challenge page, with the cookie to send along with the
+    /// page if the identity is new to the client.
+    ///
+    /// # Errors
+    /// Will return `Err` if the request does not carry a valid identity and none can be assigned.
+    fn assign(&self, req: &HttpRequest) -> Result<(ClientId, Option<Cookie<'static>>), actix_web::Error> {
+        Ok((self.identify(req)?, None))
+    }
+}
+
+/// The circuit address nginx sets from Tor's exported circuit ID, see [`get_circuit_id`], or the
+/// source address of the PROXY protocol header when Tor connects to foxyon directly.
+pub struct TorCircuit;
+
+impl ClientIdentity for TorCircuit {
+    fn identify(&self, req: &HttpRequest) -> Result<ClientId, actix_web::Error> {
+        if CONFIG.server.proxy_protocol.enabled {
+            return get_peer_circuit_id(req);
+        }
+        get_circuit_id(req.headers())
+    }
+}
+
+/// Extracts the circuit identity from the `X-Circuit-Id` header, or the configured `header`.
+///
+/// The Tor circuit ID is provided as an IPv6-style string (e.g. `fc00:dead:beef:4dad::12d`)
+/// whose last 4 bytes are the numeric circuit ID. The whole address is kept, see [`ClientId`],
+/// with its fourth group replaced by the `instance_header` value when one is configured and sent.
+///
+/// # Errors
+///
+/// Will return `ErrorInternalServerError` if `X-Circuit-Id` is missing or not valid UTF-8,
+/// and `ErrorForbidden` if it is not an address of the configured network.
+fn get_circuit_id(headers: &HeaderMap) -> Result<ClientId, actix_web::Error> {
+    let circuit_id_str = if let Some(h) = headers.get(CONFIG.circuit.header.as_str()) {
+        h
+            .to_str()
+            .map_err(|e| {
+                error!(error = ?e, "Error parsing circuit_id");
+                error::ErrorInternalServerError("Invalid X-Circuit-Id header")
+            })?
+    } else {
+        error!("X-Circuit-Id header is missing");
+        return Err(error::ErrorInternalServerError("X-Circuit-Id header is missing"))
+    };
+
+    ClientId::from_circuit(circuit_id_str, instance_tag(headers))
+        .ok_or_else(|| error::ErrorForbidden("X-Circuit-Id is not a circuit address"))
+}
+
+/// Extracts the circuit identity from the peer address, the source address of the PROXY
+/// protocol header sent by `HiddenServiceExportCircuitID haproxy`.
+///
+/// # Errors
+///
+/// Will return `ErrorForbidden` if the peer address is unknown or not a circuit address of the
+/// configured network, e.g. when a connection from an untrusted source sent no header.
+fn get_peer_circuit_id(req: &HttpRequest) -> Result<ClientId, actix_web::Error> {
+    match req.peer_addr().map(|peer| peer.ip()) {
+        Some(IpAddr::V6(address)) => ClientId::from_circuit_addr(address, instance_tag(req.headers()))
+            .ok_or_else(|| error::ErrorForbidden("Peer address is not a circuit address")),
+        _ => Err(error::ErrorForbidden("Peer address is not a circuit address")),
+    }
+}
+
+/// Tag of the Tor instance the request came through, from the configured `instance_header`.
+fn instance_tag(headers: &HeaderMap) -> Option<u16> {
+    if CONFIG.circuit.instance_header.is_empty() {
+        return None;
+    }
+    headers.get(CONFIG.circuit.instance_header.as_str())
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// The client IP, taken from `X-Forwarded-For` or `X-Real-IP` only when the peer is a trusted proxy.
+pub struct ForwardedIp {
+    header: ForwardedHeader,
+    trusted: Vec<Network>,
+}
+
+impl ForwardedIp {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            header: CONFIG.identity.forwarded.header,
+            trusted: Network::parse_all(&CONFIG.identity.forwarded.trusted_proxies),
+        }
+    }
+
+    #[inline]
+    fn trusts(&self, ip: IpAddr) -> bool {
+        let address = ClientId::from_ip(ip).0;
+        self.trusted.iter().any(|network| network.contains(address))
+    }
+
+    /// Walks the hops from `peer` back towards the client, stopping at the first untrusted one.
+    ///
+    /// Everything left of it may have been written by the client, so it is never read.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+        match self.header {
+            ForwardedHeader::XRealIp => headers.get("X-Real-IP")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(peer),
+            ForwardedHeader::XForwardedFor => {
+                let hops: Vec<&str> = headers.get_all("X-Forwarded-For")
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .collect();
+                let mut client = peer;
+                for hop in hops.iter().rev() {
+                    let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+                        break;
+                    };
+                    client = ip;
+                    if !self.trusts(ip) {
+                        break;
+                    }
+                }
+                client
+            }
+        }
+    }
+}
+
+impl Default for ForwardedIp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientIdentity for ForwardedIp {
+    fn identify(&self, req: &HttpRequest) -> Result<ClientId, actix_web::Error> {
+        let Some(peer) = req.peer_addr() else {
+            error!("Peer address is unknown, forwarded identities need a TCP listener");
+            return Err(error::ErrorInternalServerError("Unknown peer address"));
+        };
+        Ok(ClientId::from_ip(self.client_ip(peer.ip(), req.headers())))
+    }
+}
+
+/// A random client ID kept by the browser in an `HttpOnly` cookie, signed with the keyed hash.
+///
+/// The cookie is issued with the challenge page, so the submissions, failures and limits of a
+/// client add up from its first page on. A client dropping it gets a new identity with every
+/// page, and can not submit a solution without it; those pages are limited and penalized
+/// together under [`ClientId::UNIDENTIFIED`].
+pub struct SignedCookie;
+
+impl SignedCookie {
+    /// Client ID of a cookie value, `None` if it was not signed by this server.
+    fn verify(value: &str) -> Option<ClientId> {
+        if value.len() != URL_SAFE_NO_PAD.encoded_length(COOKIE_LEN) {
+            return None;
+        }
+        let mut buf = [0u8; COOKIE_LEN];
+        let raw = URL_SAFE_NO_PAD.decode(value.as_bytes(), Out::from_slice(&mut buf)).ok()?;
+        let (id, mac) = raw.split_first_chunk::<16>()?;
+        let expected = identity_cookie_mac(id);
+        bool::from(mac.ct_eq(&expected[..COOKIE_MAC_LEN])).then(|| ClientId(u128::from_be_bytes(*id)))
+    }
+
+    fn sign(client_id: ClientId) -> Cookie<'static> {
+        let id = client_id.to_bytes();
+        let mut raw = [0u8; COOKIE_LEN];
+        raw[..16].copy_from_slice(&id);
+        raw[16..].copy_from_slice(&identity_cookie_mac(&id)[..COOKIE_MAC_LEN]);
+
+        let cfg = &CONFIG.identity.cookie;
+        Cookie::build(cfg.name.clone(), URL_SAFE_NO_PAD.encode_to_string(raw))
+            .path("/")
+            .http_only(true)
+            .secure(cfg.secure)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::seconds(i64::try_from(cfg.max_age).unwrap_or(i64::MAX)))
+            .finish()
+    }
+}
+
+impl ClientIdentity for SignedCookie {
+    fn identify(&self, req: &HttpRequest) -> Result<ClientId, actix_web::Error> {
+        req.cookie(&CONFIG.identity.cookie.name)
+            .and_then(|cookie| Self::verify(cookie.value()))
+            .ok_or_else(|| error::ErrorUnauthorized("Missing or invalid identity cookie"))
+    }
+
+    fn assign(&self, req: &HttpRequest) -> Result<(ClientId, Option<Cookie<'static>>), actix_web::Error> {
+        if let Ok(client_id) = self.identify(req) {
+            return Ok((client_id, None));
+        }
+        let client_id = ClientId(rand::rng().random());
+        Ok((client_id, Some(Self::sign(client_id))))
+    }
+}
+
+/// The extractor selected by `[identity] mode`.
+pub enum IdentityExtractor {
+    Tor(TorCircuit),
+    Forwarded(ForwardedIp),
+    Cookie(SignedCookie),
+}
+
+impl IdentityExtractor {
+    #[must_use]
+    pub fn new() -> Self {
+        match CONFIG.identity.mode {
+            IdentityMode::Tor => Self::Tor(TorCircuit),
+            IdentityMode::Forwarded => Self::Forwarded(ForwardedIp::new()),
+            IdentityMode::Cookie => Self::Cookie(SignedCookie),
+        }
+    }
+}
+
+impl Default for IdentityExtractor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientIdentity for IdentityExtractor {
+    fn identify(&self, req: &HttpRequest) -> Result<ClientId, actix_web::Error> {
+        match self {
+            Self::Tor(extractor) => extractor.identify(req),
+            Self::Forwarded(extractor) => extractor.identify(req),
+            Self::Cookie(extractor) => extractor.identify(req),
+        }
+    }
+
+    fn assign(&self, req: &HttpRequest) -> Result<(ClientId, Option<Cookie<'static>>), actix_web::Error> {
+        match self {
+            Self::Tor(extractor) => extractor.assign(req),
+            Self::Forwarded(extractor) => extractor.assign(req),
+            Self::Cookie(extractor) => extractor.assign(req),
+        }
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn networks_of_both_families(){
+        assert_eq!(Network::parse("fc00:dead:beef::/48"), Some(Network { address: 0xfc00_dead_beef_0000_0000_0000_0000_0000, len: 48 }));
+        assert_eq!(Network::parse("fc00::/129"), None);
+        assert_eq!(Network::parse("10.0.0.0/33"), None);
+        assert!(Network { address: 0, len: 0 }.contains(u128::MAX), "A /0 contains everything");
+
+        let private = Network::parse("10.0.0.0/8");
+        assert!(private.is_some_and(|network| network.contains(ClientId::from_ip(IpAddr::from([10, 1, 2, 3])).0)));
+        assert!(private.is_some_and(|network| !network.contains(ClientId::from_ip(IpAddr::from([11, 1, 2, 3])).0)));
+        assert!(IPV4_MAPPED.contains(ClientId::from_ip(IpAddr::from([192, 0, 2, 1])).0));
+        assert_eq!(Network::parse("192.0.2.0/24").map(|network| network.to_string()), Some("::ffff:192.0.2.0/120".to_owned()));
+    }
+
+    #[test]
+    fn only_forwarded_addresses_are_aggregated(){
+        let cfg = aggregation(24, 64);
+        let client = ClientId::from_ip(IpAddr::from([192, 0, 2, 77]));
+        assert!(client.prefix_in(IdentityMode::Forwarded, &cfg).is_some());
+        assert_eq!(client.prefix_in(IdentityMode::Tor, &cfg), None, "Circuit identities are not addresses");
+        assert_eq!(client.prefix_in(IdentityMode::Cookie, &cfg), None, "Cookie identities are not addresses");
+        assert_eq!(client.prefix_in(IdentityMode::Forwarded, &Aggregation { enabled: false, ..cfg }), None);
+    }
+
+    #[test]
+    fn addresses_are_masked_to_their_prefix(){
+        let prefix = |ip: IpAddr, cfg: &Aggregation| ClientId::from_ip(ip)
+            .prefix_in(IdentityMode::Forwarded, cfg)
+            .map(|network| network.to_string());
+        let v4 = IpAddr::from([192, 0, 2, 77]);
+        let v6: IpAddr = "2001:db8:0:1:abcd::1".parse().unwrap_or(IpAddr::from([0; 16]));
+
+        assert_eq!(prefix(v4, &aggregation(24, 64)), Some("::ffff:192.0.2.0/120".to_owned()));
+        assert_eq!(prefix(v6, &aggregation(24, 64)), Some("2001:db8:0:1::/64".to_owned()));
+        assert_eq!(prefix(v4, &aggregation(16, 48)), Some("::ffff:192.0.0.0/112".to_owned()));
+        assert_eq!(prefix(v6, &aggregation(16, 48)), Some("2001:db8::/48".to_owned()));
+        assert_eq!(prefix(v4, &aggregation(40, 200)), Some("::ffff:192.0.2.77/128".to_owned()), "Prefixes should be clamped to the address length");
+        assert_eq!(prefix(v6, &aggregation(40, 200)), Some("2001:db8:0:1:abcd::1/128".to_owned()));
+        assert_eq!(
+            ClientId::from_ip(IpAddr::from([192, 0, 2, 1])).prefix_in(IdentityMode::Forwarded, &aggregation(24, 64)),
+            ClientId::from_ip(IpAddr::from([192, 0, 2, 254])).prefix_in(IdentityMode::Forwarded, &aggregation(24, 64)),
+            "Addresses of a /24 should share their prefix"
+        );
+    }
+
+    #[allow(dead_code)]
+    fn aggregation(ipv4_prefix: u8, ipv6_prefix: u8) -> Aggregation {
+        Aggregation {
+            enabled: true,
+            ipv4_prefix,
+            ipv6_prefix,
+            max_failures: 20,
+            issuance_capacity: 40.0,
+            issuance_refill_per_sec: 1.0,
+            credits_capacity: 2400.0,
+            credits_refill_per_sec: 8.0,
+            points_per_bit: 40.0,
+        }
+    }
+
+    #[test]
+    fn forwarded_chain_stops_at_first_untrusted_hop(){
+        let forwarded = ForwardedIp {
+            header: ForwardedHeader::XForwardedFor,
+            trusted: ["10.0.0.0/8", "::1/128"].iter().filter_map(|network| Network::parse(network)).collect(),
+        };
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::X_FORWARDED_FOR,
+            actix_web::http::header::HeaderValue::from_static("6.6.6.6, 203.0.113.7, 10.0.0.2"),
+        );
+
+        assert_eq!(forwarded.client_ip(proxy, &headers), IpAddr::from([203, 0, 113, 7]), "Hops left of the client can be spoofed");
+        assert_eq!(forwarded.client_ip(IpAddr::from([198, 51, 100, 1]), &headers), IpAddr::from([198, 51, 100, 1]), "Untrusted peers are keyed by their own address");
+        assert_eq!(forwarded.client_ip(proxy, &HeaderMap::new()), proxy);
+    }
+
+    #[test]
+    fn cookie_round_trip(){
+        let client_id = ClientId(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        let cookie = SignedCookie::sign(client_id);
+        assert_eq!(SignedCookie::verify(cookie.value()), Some(client_id));
+
+        let mut forged = cookie.value().to_owned();
+        forged.replace_range(..1, if forged.starts_with('A') { "B" } else { "A" });
+        assert_eq!(SignedCookie::verify(&forged), None, "A changed ID should not verify");
+        assert_eq!(SignedCookie::verify("short"), None);
+    }
+}
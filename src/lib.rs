@@ -1,7 +1,18 @@
+pub mod cgroup;
+pub mod circuit;
+pub mod challenge_pool;
 pub mod config;
 pub mod crypto;
+pub mod difficulty;
+pub mod identity;
+pub mod load;
+pub mod proxy;
+pub mod proxy_protocol;
 pub mod routes;
 pub mod pow;
 pub mod session;
 mod allocator;
 pub mod system;
+pub mod telemetry;
+pub mod tor;
+pub mod upstream;
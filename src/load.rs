@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::config::{LoadInput, LoadWeights};
+
+/// Request counters shared by the handlers and sampled by the load signal task.
+#[derive(Default)]
+pub struct LoadMetrics {
+    challenges_issued: AtomicU64,
+    auth_requests: AtomicU64,
+    auth_rejected: AtomicU64,
+    in_flight: AtomicU64,
+    completed: AtomicU64,
+    latency_us: AtomicU64,
+    penalized_challenges: AtomicU64,
+    penalty_bits: AtomicU64,
+}
+
+/// Decrements the in-flight gauge when the request that created it finishes, and adds the
+/// time it took to the latency counters.
+pub struct InFlight<'a> {
+    metrics: &'a LoadMetrics,
+    started: Instant,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let elapsed = u64::try_from(self.started.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.completed.fetch_add(1, Ordering::Relaxed);
+        self.metrics.latency_us.fetch_add(elapsed, Ordering::Relaxed);
+    }
+}
+
+impl LoadMetrics {
+    #[inline]
+    pub fn challenge_issued(&self) {
+        self.challenges_issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn auth(&self, authorized: bool) {
+        self.auth_requests.fetch_add(1, Ordering::Relaxed);
+        if !authorized {
+            self.auth_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a challenge issued with `extra_bits` added by its circuit history.
+    ///
+    /// The counts are totals since startup, read through [`LoadMetrics::circuit_penalties`].
+    #[inline]
+    pub fn circuit_penalty(&self, extra_bits: u8) {
+        if extra_bits > 0 {
+            self.penalized_challenges.fetch_add(1, Ordering::Relaxed);
+            self.penalty_bits.fetch_add(u64::from(extra_bits), Ordering::Relaxed);
+        }
+    }
+
+    /// Challenges issued with a per-circuit penalty since startup, and their extra bits.
+    #[must_use]
+    pub fn circuit_penalties(&self) -> CircuitPenalties {
+        CircuitPenalties {
+            challenges: self.penalized_challenges.load(Ordering::Relaxed),
+            bits: self.penalty_bits.load(Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn track(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight { metrics: self, started: Instant::now() }
+    }
+
+    /// Drains the counters accumulated during `elapsed` into per-second rates.
+    #[must_use]
+    pub fn sample(&self, cpu: f64, elapsed: Duration) -> LoadSample {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let challenges = to_f64(self.challenges_issued.swap(0, Ordering::Relaxed));
+        let auth_requests = to_f64(self.auth_requests.swap(0, Ordering::Relaxed));
+        let auth_rejected = to_f64(self.auth_rejected.swap(0, Ordering::Relaxed));
+        let completed = to_f64(self.completed.swap(0, Ordering::Relaxed));
+        let latency_ms = to_f64(self.latency_us.swap(0, Ordering::Relaxed)) / 1000.0;
+
+        LoadSample {
+            cpu,
+            challenge_rate: challenges / seconds,
+            auth_rate: auth_requests / seconds,
+            auth_reject_ratio: if auth_requests > 0.0 { auth_rejected / auth_requests } else { 0.0 },
+            in_flight: to_f64(self.in_flight.load(Ordering::Relaxed)),
+            latency: if completed > 0.0 { latency_ms / completed } else { 0.0 },
+            ..LoadSample::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadSample {
+    pub cpu: f64,
+    pub challenge_rate: f64,
+    pub auth_rate: f64,
+    pub auth_reject_ratio: f64,
+    pub in_flight: f64,
+    /// Mean milliseconds the requests completed during the sample took.
+    pub latency: f64,
+    pub throttled: f64,
+    pub psi_cpu: f64,
+    pub psi_memory: f64,
+    pub psi_io: f64,
+    pub upstream_latency: f64,
+    pub upstream_errors: f64,
+    pub upstream_active: f64,
+}
+
+/// Per-circuit penalties applied to issued challenges, reported but not part of the pressure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CircuitPenalties {
+    pub challenges: u64,
+    /// Sum of the extra bits of those challenges.
+    pub bits: u64,
+}
+
+impl LoadSample {
+    /// Combines every input into a single pressure score between 0 and 100.
+    ///
+    /// Each input is scaled against its saturation point and multiplied by its weight,
+    /// so an input with weight `1.0` can drive the score to 100 on its own.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn pressure(&self, weights: &LoadWeights) -> f32 {
+        let score = scaled(self.cpu, &weights.cpu)
+            + scaled(self.challenge_rate, &weights.challenge_rate)
+            + scaled(self.auth_rate, &weights.auth_rate)
+            + scaled(self.auth_reject_ratio, &weights.auth_reject_ratio)
+            + scaled(self.in_flight, &weights.in_flight)
+            + scaled(self.latency, &weights.latency)
+            + scaled(self.throttled, &weights.throttled)
+            + scaled(self.psi_cpu, &weights.psi_cpu)
+            + scaled(self.psi_memory, &weights.psi_memory)
+            + scaled(self.psi_io, &weights.psi_io)
+            + scaled(self.upstream_latency, &weights.upstream_latency)
+            + scaled(self.upstream_errors, &weights.upstream_errors)
+            + scaled(self.upstream_active, &weights.upstream_active);
+        // Within 0-100 the score fits an f32, the type the tiers are configured in.
+        score.clamp(0.0, 100.0) as f32
+    }
+}
+
+#[inline]
+fn scaled(value: f64, input: &LoadInput) -> f64 {
+    if input.saturation <= 0.0 {
+        return 0.0;
+    }
+    (value / input.saturation).clamp(0.0, 1.0) * 100.0 * input.weight
+}
+
+#[inline]
+pub(crate) fn to_f64(value: u64) -> f64 {
+    f64::from(u32::try_from(value).unwrap_or(u32::MAX))
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn pressure_is_driven_by_any_saturated_input(){
+        let input = |weight| LoadInput { weight, saturation: 100.0 };
+        let weights = LoadWeights {
+            cpu: input(1.0),
+            challenge_rate: input(1.0),
+            auth_rate: input(0.5),
+            auth_reject_ratio: input(0.0),
+            in_flight: input(0.0),
+            latency: input(0.0),
+            throttled: input(0.0),
+            psi_cpu: input(0.0),
+            psi_memory: input(0.0),
+            psi_io: input(0.0),
+            upstream_latency: input(0.0),
+            upstream_errors: input(0.0),
+            upstream_active: input(0.0),
+        };
+        let idle = LoadSample::default();
+        assert!(idle.pressure(&weights).abs() < f32::EPSILON);
+
+        let flood = LoadSample { challenge_rate: 5000.0, ..LoadSample::default() };
+        assert!((flood.pressure(&weights) - 100.0).abs() < f32::EPSILON, "A saturated input with weight 1.0 should max out the score");
+
+        let partial = LoadSample { auth_rate: 100.0, ..LoadSample::default() };
+        assert!((partial.pressure(&weights) - 50.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn sample_drains_counters(){
+        let metrics = LoadMetrics::default();
+        metrics.auth(true);
+        metrics.auth(false);
+        let guard = metrics.track();
+
+        let sample = metrics.sample(0.0, Duration::from_secs(2));
+        assert!((sample.auth_rate - 1.0).abs() < f64::EPSILON);
+        assert!((sample.auth_reject_ratio - 0.5).abs() < f64::EPSILON);
+        assert!((sample.in_flight - 1.0).abs() < f64::EPSILON);
+        assert!(sample.latency.abs() < f64::EPSILON, "Requests still in flight have no latency yet");
+
+        std::thread::sleep(Duration::from_millis(5));
+        drop(guard);
+        let sample = metrics.sample(0.0, Duration::from_secs(2));
+        assert!(sample.auth_rate.abs() < f64::EPSILON, "Counters should be reset after sampling");
+        assert!(sample.in_flight.abs() < f64::EPSILON);
+        assert!(sample.latency >= 5.0, "Finished requests should report the time they took");
+    }
+
+    #[test]
+    fn circuit_penalties_accumulate(){
+        let metrics = LoadMetrics::default();
+        metrics.circuit_penalty(0);
+        assert_eq!(metrics.circuit_penalties(), CircuitPenalties::default(), "Challenges without a penalty should not be counted");
+
+        metrics.circuit_penalty(2);
+        metrics.circuit_penalty(3);
+        let _ = metrics.sample(0.0, Duration::from_secs(1));
+        assert_eq!(metrics.circuit_penalties(), CircuitPenalties { challenges: 2, bits: 5 }, "Sampling the load should not reset the totals");
+    }
+}
@@ -1,22 +1,42 @@
+use std::net::ToSocketAddrs;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use foxyon::{
-    config::CONFIG,
+    challenge_pool::{ChallengePool, challenge_producer},
+    config::{CONFIG, IdentityMode},
+    difficulty::Ladder,
+    identity::IdentityExtractor,
+    load::LoadMetrics,
+    proxy::ReverseProxy,
+    proxy_protocol::{ProxyProtocol, http_service},
     routes::{
-        auth::auth,
-        challenge::{challenge_page, challenge_post}
+        auth::{auth, forward_auth},
+        challenge::{challenge_page, challenge_post},
+        kill::kill,
+        proxy::proxy,
+        renew::{renew_page, renew_post},
+        tarpit::Tarpit
     },
     session::{
         SessionCache,
-        challenge_blacklist::ChallengeBlacklist
+        ban::BanList,
+        challenge_blacklist::ChallengeBlacklist,
+        circuit_history::CircuitHistory,
+        issuance::IssuanceLimiter,
+        token::TokenDenyList
     },
-    system::cpu_usage,
+    system::load_signal,
+    telemetry::SolveTelemetry,
+    tor::{TorControl, circuit_events},
+    upstream::{UpstreamStatus, upstream_probe},
 };
 
 use tokio::{sync::watch, task};
 
 use actix_web::{web, App, HttpServer};
-use tracing::Level;
+use actix_server::Server;
+use tracing::{Level, warn};
 use tracing_subscriber::fmt;
 
 #[actix_web::main]
@@ -28,26 +48,121 @@ async fn main() -> std::io::Result<()> {
         }))
         .init();
 
-    let (tx_cpu_usage, rx_cpu_usage) = watch::channel(0f32);
+    let initial_bits = Ladder::new(&CONFIG.pow.difficulty.tiers, CONFIG.pow.difficulty.hysteresis).bits();
+    let (tx_difficulty, rx_difficulty) = watch::channel(initial_bits);
+
+    let (tx_upstream, rx_upstream) = watch::channel(UpstreamStatus::default());
+
+    if CONFIG.upstream.enabled {
+        task::spawn(async move {
+            upstream_probe(tx_upstream).await;
+        });
+    }
+
+    let metrics = web::Data::new(LoadMetrics::default());
+    let load_metrics = metrics.clone().into_inner();
+    let load_upstream = rx_upstream.clone();
+    let telemetry = web::Data::new(SolveTelemetry::new(CONFIG.pow.telemetry.window));
+    let load_telemetry = telemetry.clone().into_inner();
 
     task::spawn(async move {
-        cpu_usage(tx_cpu_usage).await;
+        load_signal(tx_difficulty, load_metrics, load_upstream, load_telemetry).await;
     });
 
     let session = web::Data::new(SessionCache::new());
+    if CONFIG.identity.mode == IdentityMode::Tor && CONFIG.tor.enabled && CONFIG.tor.circuit_events {
+        let event_session = session.clone().into_inner();
+        actix_web::rt::spawn(async move {
+            circuit_events(event_session).await;
+        });
+    } else if CONFIG.identity.mode == IdentityMode::Tor {
+        warn!(
+            ttl = CONFIG.tor.fallback_max_ttl,
+            tti = CONFIG.tor.fallback_max_tti,
+            "Tor circuit events are disabled, session lifetimes are capped"
+        );
+    }
     let nonce_filter = web::Data::new(ChallengeBlacklist::default());
-    let cpu_usage = web::Data::new(rx_cpu_usage);
+    let history = web::Data::new(CircuitHistory::default());
+    let bans = web::Data::new(BanList::default());
+    let issuance = web::Data::new(IssuanceLimiter::default());
+    let tarpit = web::Data::new(Tarpit::default());
+    let tor = web::Data::new(TorControl::default());
+    let identity = web::Data::new(IdentityExtractor::default());
+    let tokens = web::Data::new(TokenDenyList::default());
+    let reverse_proxy = web::Data::new(ReverseProxy::default());
+    let pool = web::Data::new(ChallengePool::default());
+    if CONFIG.pow.pool.enabled {
+        let producer_pool = pool.clone().into_inner();
+        let producer_difficulty = rx_difficulty.clone();
+        task::spawn(async move {
+            challenge_producer(producer_pool, producer_difficulty).await;
+        });
+    }
+    let difficulty = web::Data::new(rx_difficulty);
+    let upstream = web::Data::new(rx_upstream);
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(cpu_usage.clone())
+    let app = move || {
+        let app = App::new()
+            .app_data(difficulty.clone())
+            .app_data(metrics.clone())
+            .app_data(upstream.clone())
+            .app_data(telemetry.clone())
             .app_data(session.clone())
             .app_data(nonce_filter.clone())
+            .app_data(history.clone())
+            .app_data(bans.clone())
+            .app_data(issuance.clone())
+            .app_data(pool.clone())
+            .app_data(tarpit.clone())
+            .app_data(tor.clone())
+            .app_data(identity.clone())
+            .app_data(tokens.clone())
+            .app_data(reverse_proxy.clone())
             .route(&CONFIG.routes.challenge, web::get().to(challenge_page))
-            .route(&CONFIG.routes.auth, web::get().to(auth))
-            .route(&CONFIG.routes.challenge, web::post().to(challenge_post))
-    })
-        .bind(format!("{}:{}", &CONFIG.server.host, &CONFIG.server.port))?
+            .route(&CONFIG.routes.challenge, web::post().to(challenge_post));
+        let app = if CONFIG.forward_auth.enabled {
+            app.route(&CONFIG.routes.auth, web::get().to(forward_auth))
+        } else {
+            app.route(&CONFIG.routes.auth, web::get().to(auth))
+        };
+        let app = if CONFIG.renewal.enabled {
+            app
+                .route(&CONFIG.routes.renew, web::get().to(renew_page))
+                .route(&CONFIG.routes.renew, web::post().to(renew_post))
+        } else {
+            app
+        };
+        let app = if CONFIG.security.kill_token.is_empty() {
+            app
+        } else {
+            app.route(&CONFIG.routes.kill, web::post().to(kill))
+        };
+        if CONFIG.proxy.enabled {
+            app.default_service(web::to(proxy))
+        } else {
+            app
+        }
+    };
+    let address = format!("{}:{}", &CONFIG.server.host, &CONFIG.server.port);
+
+    if CONFIG.server.proxy_protocol.enabled {
+        // The header's source address is the peer address of the requests. Every address is
+        // bound on its own, so requests without a Host get it as theirs like with HttpServer.
+        let proxy = Arc::new(ProxyProtocol::new());
+        let keep_alive = Duration::from_secs(CONFIG.server.keep_alive);
+        let mut server = Server::build()
+            .backlog(CONFIG.server.backlog)
+            .max_concurrent_connections(CONFIG.server.max_connections)
+            .workers(CONFIG.server.workers);
+        for local in address.to_socket_addrs()? {
+            server = server.bind("foxyon", local, http_service(proxy.clone(), local, keep_alive, app.clone()))?;
+        }
+        return server.run().await;
+    }
+
+    HttpServer::new(app)
+        .bind(address)?
         .backlog(CONFIG.server.backlog)
         .max_connections(CONFIG.server.max_connections)
         .keep_alive(Duration::from_secs(CONFIG.server.keep_alive))
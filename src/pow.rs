@@ -1,5 +1,6 @@
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::crypto::blake3::{pow_integrity_hash, pow_challenge_hash};
+use crate::crypto::blake3::{pow_integrity_hash, pow_challenge_hash, pow_penalized_hash, pow_renewal_hash};
+use crate::identity::ClientId;
 use crate::config::CONFIG;
 
 use sailfish::TemplateOnce;
@@ -27,20 +28,36 @@ pub struct Challenge {
 }
 
 impl Challenge {
-    pub fn new(cpu_usage: &Receiver<f32>) -> Challenge {
+    /// Challenge at the current difficulty plus `extra_bits` earned by the requesting circuit.
+    ///
+    /// The extra bits are signed into the challenge, so its session is created at the base difficulty.
+    pub fn new(difficulty: &Receiver<u8>, extra_bits: u8) -> Challenge {
+        let base_bits = *difficulty.borrow();
+        let difficulty_bits: u8 = base_bits.saturating_add(extra_bits);
+
+        #[cfg(feature = "debug")]
+        info!("Challenge difficulty bits is {difficulty_bits}");
+
+        Self::generate(difficulty_bits, Signature::Penalized(difficulty_bits.saturating_sub(base_bits)))
+    }
+
+    /// Challenge at exactly `difficulty_bits`.
+    #[must_use]
+    pub fn with_bits(difficulty_bits: u8) -> Challenge {
+        Self::generate(difficulty_bits, Signature::Penalized(0))
+    }
+
+    /// Challenge that extends the session of `client_id` instead of creating one.
+    #[must_use]
+    pub fn renewal(difficulty_bits: u8, client_id: ClientId) -> Challenge {
+        Self::generate(difficulty_bits, Signature::Renewal(client_id))
+    }
+
+    fn generate(difficulty_bits: u8, signature: Signature) -> Challenge {
         let challenge: [u8; CHALLENGE_LEN] = {
             let mut rng = rand::rng();
             std::array::from_fn(|_| rng.sample(Alphanumeric))
         };
-        let difficulty_bits: u8 = match *cpu_usage.borrow() {
-            cpu if cpu < CONFIG.pow.cpu_thresholds.low => CONFIG.pow.difficulty.minimum,
-            cpu if cpu <  CONFIG.pow.cpu_thresholds.medium => CONFIG.pow.difficulty.medium,
-            cpu if cpu < CONFIG.pow.cpu_thresholds.high => CONFIG.pow.difficulty.high,
-            _ => CONFIG.pow.difficulty.ultra
-        };
-
-        #[cfg(feature = "debug")]
-        info!("Challenge difficulty bits is {difficulty_bits} and CPU usage at {}", *cpu_usage.borrow());
 
         let expires_at: u64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
             Ok(d) => d.as_secs(),
@@ -48,12 +65,17 @@ impl Challenge {
                 error!(error = ?e, "System time is before UNIX_EPOCH; using 0 as fallback for expiration");
                 0
             },
-        }.saturating_add(CONFIG.pow.challenge_ttl);
+        }.saturating_add(challenge_ttl(difficulty_bits));
 
 
         let integrity_b64: [u8; B64_LEN] = {
             let mut buf = [0u8; B64_LEN];
-            let _ = STANDARD_NO_PAD.encode(&pow_integrity_hash(&challenge, difficulty_bits, expires_at), Out::from_slice(&mut buf));
+            let integrity = match signature {
+                Signature::Penalized(0) => pow_integrity_hash(&challenge, difficulty_bits, expires_at),
+                Signature::Penalized(penalty) => pow_penalized_hash(&challenge, difficulty_bits, expires_at, penalty),
+                Signature::Renewal(client_id) => pow_renewal_hash(&challenge, difficulty_bits, expires_at, &client_id.to_bytes()),
+            };
+            let _ = STANDARD_NO_PAD.encode(&integrity, Out::from_slice(&mut buf));
             buf
         };
 
@@ -73,6 +95,13 @@ impl Challenge {
         unsafe { std::str::from_utf8_unchecked(&self.challenge) }
     }
 
+    /// Path the form posts the solution to, the configured challenge route.
+    #[inline]
+    #[must_use]
+    pub fn form_action(&self) -> &'static str {
+        &CONFIG.routes.challenge
+    }
+
     #[inline]
     #[must_use]
     pub fn integrity_b64_str(&self) -> &str {
@@ -82,10 +111,60 @@ impl Challenge {
     }
 }
 
+/// What the integrity hash of a challenge is bound to besides its own fields.
+#[derive(Clone, Copy)]
+enum Signature {
+    /// Bits of the difficulty added by the circuit's history, none for most challenges.
+    Penalized(u8),
+    Renewal(ClientId),
+}
+
+/// Seconds a challenge of `difficulty_bits` stays valid.
+///
+/// A solver needs `2^bits` hashes on average, so the lifetime is the time a client hashing at
+/// `reference_hashrate` takes for them, multiplied by `safety_factor`. It is never shorter than
+/// `challenge_ttl` nor longer than `max_challenge_ttl`.
+#[must_use]
+pub fn challenge_ttl(difficulty_bits: u8) -> u64 {
+    let pow = &CONFIG.pow;
+    scaled_ttl(difficulty_bits, pow.challenge_ttl, pow.max_challenge_ttl, pow.reference_hashrate, pow.safety_factor)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn scaled_ttl(difficulty_bits: u8, min: u64, max: u64, reference_hashrate: f64, safety_factor: f64) -> u64 {
+    let max = max.max(min);
+    if reference_hashrate <= 0.0 {
+        return min;
+    }
+    let expected_secs = 2f64.powi(i32::from(difficulty_bits)) / reference_hashrate * safety_factor;
+    // The value is clamped into the range of the configured bounds before the cast.
+    let secs = expected_secs.ceil().clamp(0.0, f64::from(u32::try_from(max).unwrap_or(u32::MAX))) as u64;
+    secs.clamp(min, max)
+}
+
+/// Unix timestamp at which a challenge of `difficulty_bits` expiring at `expires_at` was issued.
+#[inline]
+#[must_use]
+pub fn issued_at(difficulty_bits: u8, expires_at: u64) -> u64 {
+    expires_at.saturating_sub(challenge_ttl(difficulty_bits))
+}
+
+/// Penalty bits included in `difficulty_bits`, `None` if the integrity check fails.
+///
+/// Challenges without a penalty are checked first, as nearly every challenge is one.
+#[must_use]
+pub fn signed_penalty(challenge: &[u8], difficulty_bits: u8, expires_at: u64, client_integrity: &[u8]) -> Option<u8> {
+    if bool::from(pow_integrity_hash(challenge, difficulty_bits, expires_at).ct_eq(client_integrity)) {
+        return Some(0);
+    }
+    (1..=CONFIG.pow.circuit_penalty.max_extra_bits.min(difficulty_bits))
+        .find(|penalty| pow_penalized_hash(challenge, difficulty_bits, expires_at, *penalty).ct_eq(client_integrity).into())
+}
+
 #[inline]
 #[must_use]
-pub fn check_integrity(challenge: &[u8], difficulty_bits: u8, expires_at: u64, client_integrity: &[u8]) -> bool {
-    pow_integrity_hash(challenge, difficulty_bits, expires_at)
+pub fn check_renewal_integrity(challenge: &[u8], difficulty_bits: u8, expires_at: u64, client_id: ClientId, client_integrity: &[u8]) -> bool {
+    pow_renewal_hash(challenge, difficulty_bits, expires_at, &client_id.to_bytes())
         .ct_eq(client_integrity).into()
 }
 
@@ -93,4 +172,38 @@ pub fn check_integrity(challenge: &[u8], difficulty_bits: u8, expires_at: u64, c
 #[must_use]
 pub fn validate_challenge(client_work: &[u8], challenge: &[u8], difficulty_bits: u8, expires_at: u64) -> bool {
     U256::from_little_endian(&pow_challenge_hash(client_work, challenge, expires_at)).trailing_zeros() >= difficulty_bits.into()
-}
\ No newline at end of file
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn challenge_ttl_grows_with_difficulty(){
+        // 2^20 hashes at 50000 H/s take ~21s, tripled by the safety factor.
+        assert_eq!(scaled_ttl(20, 20, 300, 50_000.0, 3.0), 63);
+        assert_eq!(scaled_ttl(10, 20, 300, 50_000.0, 3.0), 20, "Easy challenges should get the minimum lifetime");
+        assert_eq!(scaled_ttl(40, 20, 300, 50_000.0, 3.0), 300, "Hard challenges should be capped at the maximum");
+        assert_eq!(scaled_ttl(20, 20, 300, 0.0, 3.0), 20, "Without a reference hashrate the minimum is used");
+
+        let ttls: Vec<u64> = (0..=64).map(|bits| scaled_ttl(bits, 20, 300, 50_000.0, 3.0)).collect();
+        assert!(ttls.windows(2).all(|pair| pair.first() <= pair.last()), "Lifetime should never shrink as difficulty grows");
+    }
+
+    #[test]
+    fn penalty_is_recovered_from_the_integrity(){
+        let integrity = |challenge: &Challenge| {
+            let mut buf = [0u8; 32];
+            STANDARD_NO_PAD.decode(&challenge.integrity_b64, Out::from_slice(&mut buf)).map(|i| i.to_vec())
+        };
+
+        let plain = Challenge::with_bits(20);
+        assert!(integrity(&plain).is_ok_and(|i| signed_penalty(&plain.challenge, 20, plain.expires_at, &i) == Some(0)));
+
+        let penalty = CONFIG.pow.circuit_penalty.max_extra_bits.min(2);
+        let penalized = Challenge::generate(20, Signature::Penalized(penalty));
+        assert!(integrity(&penalized).is_ok_and(|i| signed_penalty(&penalized.challenge, 20, penalized.expires_at, &i) == Some(penalty)));
+        assert!(integrity(&penalized).is_ok_and(|i| signed_penalty(&penalized.challenge, 21, penalized.expires_at, &i).is_none()),
+            "A raised difficulty should fail the integrity check");
+    }
+}
@@ -0,0 +1,515 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use crate::config::CONFIG;
+use crate::upstream::plain_http_address;
+use actix_web::{HttpRequest, HttpResponse, error, web};
+use actix_web::body::SizedStream;
+use actix_web::error::PayloadError;
+use actix_web::http::{StatusCode, header as actix_header};
+use actix_web::web::{Bytes, BytesMut};
+use ada_url::Url;
+use futures_util::{StreamExt, stream::{self, BoxStream}};
+use http_body_util::{BodyStream, Either, Empty, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, Request, Uri};
+use hyper_util::client::legacy::{Client, connect::{Connected, Connection}};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tower_service::Service;
+use tracing::error;
+
+/// Headers that only apply to one connection and are never forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+/// Prefix of the headers `auth` answers with, which clients must not be able to send upstream.
+const SESSION_HEADER_PREFIX: &str = "x-foxyon-";
+/// Bytes read from the upstream at a time once a websocket is switched.
+const UPGRADE_BUFFER: usize = 16 * 1024;
+/// Authority of the URI requests are sent to, which only keys the connection pool: the
+/// connector always dials the configured upstream, and the `Host` header is the client's.
+const POOL_AUTHORITY: &str = "upstream";
+
+/// Request body: the client's, streamed, or nothing for websocket handshakes whose payload is
+/// the websocket itself.
+type ProxyBody = Either<StreamBody<BoxStream<'static, Result<Frame<Bytes>, PayloadError>>>, Empty<Bytes>>;
+
+trait UpstreamStream: AsyncRead + AsyncWrite + Connection + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Connection + Unpin + Send> UpstreamStream for S {}
+
+impl Connection for Box<dyn UpstreamStream> {
+    fn connected(&self) -> Connected {
+        (**self).connected()
+    }
+}
+
+/// Dials the upstream, over its unix socket if one is configured.
+#[derive(Clone)]
+struct UpstreamConnector {
+    /// `host:port` to connect to, `None` if the configured URL is invalid or not `http://`.
+    address: Option<Arc<str>>,
+}
+
+impl UpstreamConnector {
+    async fn connect(address: Option<Arc<str>>) -> io::Result<Box<dyn UpstreamStream>> {
+        if !CONFIG.proxy.unix_socket.is_empty() {
+            Ok(Box::new(UnixStream::connect(&CONFIG.proxy.unix_socket).await?))
+        } else if let Some(address) = address {
+            Ok(Box::new(TcpStream::connect(&*address).await?))
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid proxy URL"))
+        }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TokioIo<Box<dyn UpstreamStream>>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let address = self.address.clone();
+        Box::pin(async move {
+            timeout(Duration::from_millis(CONFIG.proxy.connect_timeout_ms), Self::connect(address))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "upstream connection timed out")))
+                .map(TokioIo::new)
+        })
+    }
+}
+
+/// Forwards authenticated requests to the protected upstream over HTTP/1.1, for deployments
+/// without nginx in front.
+///
+/// Bodies are streamed both ways and websocket upgrades are bridged once the upstream switched
+/// protocols. Upstream connections are pooled and kept alive between requests.
+pub struct ReverseProxy {
+    client: Client<UpstreamConnector, ProxyBody>,
+    /// `Host` sent when the client did not send one.
+    host: String,
+    /// Names of foxyon's own cookies, which are never sent upstream.
+    own_cookies: [String; 2],
+}
+
+impl ReverseProxy {
+    #[must_use]
+    pub fn new() -> Self {
+        match Url::parse(&CONFIG.proxy.url, None) {
+            Ok(url) => {
+                let address = plain_http_address(&url);
+                if address.is_none() && CONFIG.proxy.unix_socket.is_empty() {
+                    error!(url = %CONFIG.proxy.url, "Only http:// proxy URLs are supported, requests can not be forwarded");
+                }
+                Self::with_upstream(address, url.host().to_owned())
+            }
+            Err(e) => {
+                error!(error = ?e, url = %CONFIG.proxy.url, "Invalid proxy URL, requests can not be forwarded");
+                Self::with_upstream(None, String::new())
+            }
+        }
+    }
+
+    fn with_upstream(address: Option<String>, host: String) -> Self {
+        let client = Client::builder(TokioExecutor::new())
+            .set_host(false)
+            .build(UpstreamConnector { address: address.map(Arc::from) });
+        let own_cookies = [CONFIG.session.cookie.name.clone(), CONFIG.identity.cookie.name.clone()];
+        Self { client, host, own_cookies }
+    }
+
+    /// Forwards `req` with its `payload`, adding the session headers of the `auth` verdict.
+    ///
+    /// # Errors
+    /// Will return `ErrorBadGateway` if the upstream can not be reached or answers garbage, and
+    /// `ErrorGatewayTimeout` if it does not answer within `response_timeout_ms`.
+    pub async fn forward(&self, req: &HttpRequest, payload: web::Payload, session: &actix_header::HeaderMap) -> Result<HttpResponse, actix_web::Error> {
+        let bad_gateway = |e: &dyn std::fmt::Debug| {
+            error!(error = ?e, "Failed to forward request");
+            error::ErrorBadGateway("Upstream unavailable")
+        };
+
+        let upgrade = websocket_upgrade(req);
+        let (body, payload) = if upgrade.is_some() {
+            (Either::Right(Empty::new()), Some(payload))
+        } else {
+            (Either::Left(StreamBody::new(request_frames(payload))), None)
+        };
+
+        let mut request = Request::new(body);
+        *request.method_mut() = Method::from_bytes(req.method().as_str().as_bytes()).map_err(|e| bad_gateway(&e))?;
+        *request.uri_mut() = Uri::builder()
+            .scheme("http")
+            .authority(POOL_AUTHORITY)
+            .path_and_query(req.uri().path_and_query().map_or("/", |path| path.as_str()))
+            .build()
+            .map_err(|e| bad_gateway(&e))?;
+        self.copy_request_headers(req, session, request.headers_mut(), upgrade.as_ref());
+
+        let mut response = timeout(Duration::from_millis(CONFIG.proxy.response_timeout_ms), self.client.request(request))
+            .await
+            .map_err(|_| {
+                error!(timeout_ms = CONFIG.proxy.response_timeout_ms, "Upstream did not answer in time");
+                error::ErrorGatewayTimeout("Upstream timed out")
+            })?
+            .map_err(|e| bad_gateway(&e))?;
+        let switched = (response.status() == hyper::StatusCode::SWITCHING_PROTOCOLS).then(|| hyper::upgrade::on(&mut response));
+        let (parts, body) = response.into_parts();
+
+        let status = StatusCode::from_u16(parts.status.as_u16()).map_err(|e| bad_gateway(&e))?;
+        let mut builder = HttpResponse::build(status);
+        let listed = connection_options(parts.headers.get_all(header::CONNECTION).iter().map(HeaderValue::as_bytes));
+        for (name, value) in &parts.headers {
+            if is_hop_by_hop(name) || listed.iter().any(|option| option == name.as_str()) || *name == header::CONTENT_LENGTH {
+                continue;
+            }
+            if let Ok(value) = actix_header::HeaderValue::from_bytes(value.as_bytes()) {
+                builder.append_header((name.as_str(), value));
+            }
+        }
+
+        if let (Some(switched), Some(protocol), Some(mut payload)) = (switched, upgrade, payload) {
+            let upgraded = switched.await.map_err(|e| bad_gateway(&e))?;
+            let (upstream_read, mut upstream_write) = tokio::io::split(TokioIo::new(upgraded));
+            actix_web::rt::spawn(async move {
+                while let Some(Ok(chunk)) = payload.next().await {
+                    if upstream_write.write_all(&chunk).await.is_err() {
+                        break;
+                    }
+                }
+                let _ = upstream_write.shutdown().await;
+            });
+            let downstream = stream::unfold(Some(upstream_read), |reader| async move {
+                let mut reader = reader?;
+                let mut buf = BytesMut::with_capacity(UPGRADE_BUFFER);
+                match reader.read_buf(&mut buf).await {
+                    Ok(0) => None,
+                    Ok(_) => Some((Ok(buf.freeze()), Some(reader))),
+                    Err(e) => Some((Err(e), None)),
+                }
+            });
+            let protocol = protocol.to_str().unwrap_or("websocket").to_owned();
+            return Ok(builder.upgrade(protocol).streaming(downstream));
+        }
+
+        let length = parts.headers.get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        let frames = BodyStream::new(body).filter_map(|frame| std::future::ready(match frame {
+            Ok(frame) => frame.into_data().ok().map(Ok),
+            Err(e) => Some(Err(e)),
+        }));
+        Ok(match length {
+            Some(length) => builder.body(SizedStream::new(length, frames)),
+            None => builder.streaming(frames),
+        })
+    }
+
+    /// Copies the end-to-end headers of the client, replacing any `X-Foxyon-*` one with those
+    /// of the session, dropping foxyon's own cookies and appending the client to `X-Forwarded-For`.
+    fn copy_request_headers(&self, req: &HttpRequest, session: &actix_header::HeaderMap, headers: &mut HeaderMap, upgrade: Option<&HeaderValue>) {
+        let listed = connection_options(req.headers().get_all(actix_header::CONNECTION).map(actix_header::HeaderValue::as_bytes));
+        for (name, value) in req.headers() {
+            let name = name.as_str();
+            if is_hop_by_hop_name(name) || listed.iter().any(|option| option == name) || name.starts_with(SESSION_HEADER_PREFIX) {
+                continue;
+            }
+            let value = if name == "cookie" {
+                match value.to_str().ok().and_then(|value| self.strip_own_cookies(value)) {
+                    Some(value) => HeaderValue::from_str(&value),
+                    None => continue,
+                }
+            } else {
+                HeaderValue::from_bytes(value.as_bytes())
+            };
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), value) {
+                headers.append(name, value);
+            }
+        }
+        for (name, value) in session {
+            if name.as_str().starts_with(SESSION_HEADER_PREFIX)
+                && let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_str().as_bytes()), HeaderValue::from_bytes(value.as_bytes()))
+            {
+                headers.insert(name, value);
+            }
+        }
+
+        if !headers.contains_key(header::HOST)
+            && let Ok(host) = HeaderValue::from_str(&self.host)
+        {
+            headers.insert(header::HOST, host);
+        }
+        if let Some(peer) = req.peer_addr() {
+            let forwarded = match headers.get("x-forwarded-for").and_then(|value| value.to_str().ok()) {
+                Some(chain) => format!("{chain}, {}", peer.ip()),
+                None => peer.ip().to_string(),
+            };
+            if let Ok(forwarded) = HeaderValue::from_str(&forwarded) {
+                headers.insert("x-forwarded-for", forwarded);
+            }
+        }
+        if let Ok(scheme) = HeaderValue::from_str(req.connection_info().scheme()) {
+            headers.insert("x-forwarded-proto", scheme);
+        }
+        if let Some(protocol) = upgrade {
+            headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(header::UPGRADE, protocol.clone());
+        }
+    }
+
+    /// `Cookie` header value without foxyon's own cookies, `None` if none are left.
+    fn strip_own_cookies(&self, value: &str) -> Option<String> {
+        let kept: Vec<&str> = value.split(';')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let name = pair.split_once('=').map_or(*pair, |(name, _)| name).trim();
+                !self.own_cookies.iter().any(|own| own == name)
+            })
+            .collect();
+        (!kept.is_empty()).then(|| kept.join("; "))
+    }
+}
+
+impl Default for ReverseProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frames of the client's `payload`, handed over from a local task as the pooled client
+/// needs a `Send` body and the payload is bound to its worker.
+fn request_frames(mut payload: web::Payload) -> BoxStream<'static, Result<Frame<Bytes>, PayloadError>> {
+    let (sender, receiver) = mpsc::channel(1);
+    actix_web::rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((chunk.map(Frame::data), receiver))
+    }).boxed()
+}
+
+/// Lowercase header names listed in `Connection` header `values`, which only apply to this
+/// connection like the [`HOP_BY_HOP`] ones (RFC 7230, section 6.1).
+fn connection_options<'a>(values: impl Iterator<Item = &'a [u8]>) -> Vec<String> {
+    values
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+/// `Upgrade` value of a websocket handshake, `None` for any other request.
+fn websocket_upgrade(req: &HttpRequest) -> Option<HeaderValue> {
+    let headers = req.headers();
+    let connection = headers.get(actix_header::CONNECTION)?.to_str().ok()?;
+    let upgrade = headers.get(actix_header::UPGRADE)?.to_str().ok()?;
+    (connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        && upgrade.eq_ignore_ascii_case("websocket"))
+        .then(|| HeaderValue::from_str(upgrade).ok())
+        .flatten()
+}
+
+#[inline]
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    is_hop_by_hop_name(name.as_str())
+}
+
+#[inline]
+fn is_hop_by_hop_name(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop))
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use std::net::SocketAddr;
+    #[allow(unused_imports)]
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[allow(unused_imports)]
+    use actix_web::{FromRequest, body::to_bytes, test::TestRequest};
+    #[allow(unused_imports)]
+    use tokio::net::TcpListener;
+
+    #[allow(dead_code)]
+    fn proxy() -> ReverseProxy {
+        ReverseProxy::with_upstream(Some("127.0.0.1:8080".to_owned()), "127.0.0.1:8080".to_owned())
+    }
+
+    /// Upstream on a local port running `serve` on every connection, with the count of accepted
+    /// connections.
+    #[allow(dead_code)]
+    async fn upstream<F, Fut>(serve: F) -> (ReverseProxy, Arc<AtomicUsize>)
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap_or_else(|e| panic!("{e}"));
+        let address = listener.local_addr().unwrap_or_else(|e| panic!("{e}")).to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(serve(stream));
+            }
+        });
+        (ReverseProxy::with_upstream(Some(address.clone()), address), accepted)
+    }
+
+    /// Reads a request head from `stream`, `false` once the client closed it.
+    #[allow(dead_code)]
+    async fn read_head(stream: &mut TcpStream) -> bool {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                return false;
+            }
+            head.push(byte[0]);
+        }
+        true
+    }
+
+    #[allow(dead_code)]
+    async fn forward(proxy: &ReverseProxy, req: TestRequest) -> HttpResponse {
+        let (req, mut payload) = req.to_http_parts();
+        let payload = web::Payload::from_request(&req, &mut payload).await.unwrap_or_else(|e| panic!("{e}"));
+        proxy.forward(&req, payload, &actix_header::HeaderMap::new()).await.unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    #[allow(dead_code)]
+    fn upstream_headers(req: TestRequest) -> HeaderMap {
+        let mut session = actix_header::HeaderMap::new();
+        session.insert(actix_header::HeaderName::from_static("x-foxyon-effort"), actix_header::HeaderValue::from_static("20"));
+        session.insert(actix_header::HeaderName::from_static("x-foxyon-session-age"), actix_header::HeaderValue::from_static("5"));
+        let mut headers = HeaderMap::new();
+        proxy().copy_request_headers(&req.to_http_request(), &session, &mut headers, None);
+        headers
+    }
+
+    #[test]
+    fn session_headers_can_not_be_forged(){
+        let req = TestRequest::default()
+            .insert_header(("X-Foxyon-Effort", "0"))
+            .insert_header(("X-Foxyon-Token", "forged"))
+            .insert_header(("Accept", "text/html"));
+        let headers = upstream_headers(req);
+
+        assert_eq!(headers.get_all("x-foxyon-effort").iter().collect::<Vec<_>>(), vec![HeaderValue::from_static("20")]);
+        assert_eq!(headers.get("x-foxyon-session-age"), Some(&HeaderValue::from_static("5")));
+        assert_eq!(headers.get("x-foxyon-token"), None, "Session headers of the client should be dropped");
+        assert_eq!(headers.get(header::ACCEPT), Some(&HeaderValue::from_static("text/html")));
+        assert_eq!(headers.get(header::HOST), Some(&HeaderValue::from_static("127.0.0.1:8080")));
+    }
+
+    #[test]
+    fn client_is_appended_to_forwarded_for(){
+        let peer = SocketAddr::from(([203, 0, 113, 7], 4242));
+        let headers = upstream_headers(TestRequest::default().peer_addr(peer).insert_header(("X-Forwarded-For", "198.51.100.1")));
+        assert_eq!(headers.get("x-forwarded-for"), Some(&HeaderValue::from_static("198.51.100.1, 203.0.113.7")));
+
+        let headers = upstream_headers(TestRequest::default().peer_addr(peer));
+        assert_eq!(headers.get("x-forwarded-for"), Some(&HeaderValue::from_static("203.0.113.7")));
+        assert_eq!(headers.get("x-forwarded-proto"), Some(&HeaderValue::from_static("http")));
+    }
+
+    #[test]
+    fn own_cookies_stay_with_foxyon(){
+        let req = TestRequest::default()
+            .insert_header(("Connection", "keep-alive"))
+            .insert_header(("Cookie", "theme=dark; foxyon_session=abc; foxyon_id=def;lang=en"));
+        let headers = upstream_headers(req);
+        assert_eq!(headers.get(header::COOKIE), Some(&HeaderValue::from_static("theme=dark; lang=en")));
+        assert_eq!(headers.get(header::CONNECTION), None, "Hop-by-hop headers should be dropped");
+
+        let headers = upstream_headers(TestRequest::default().insert_header(("Cookie", "foxyon_session=abc")));
+        assert_eq!(headers.get(header::COOKIE), None, "A Cookie header of foxyon's cookies only should be dropped");
+        assert_eq!(proxy().strip_own_cookies("foxyon_sessions=1; foxyon_id"), Some("foxyon_sessions=1".to_owned()), "Only exact names should be dropped");
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_dropped(){
+        assert!(is_hop_by_hop_name("Connection"));
+        assert!(is_hop_by_hop_name("transfer-encoding"));
+        assert!(!is_hop_by_hop_name("content-type"));
+        assert!(is_hop_by_hop(&header::UPGRADE));
+
+        let req = TestRequest::default()
+            .insert_header(("Connection", "close, X-Hop"))
+            .insert_header(("X-Hop", "1"))
+            .insert_header(("X-End", "1"));
+        let headers = upstream_headers(req);
+        assert_eq!(headers.get("x-hop"), None, "Headers listed in Connection should be dropped");
+        assert_eq!(headers.get("x-end"), Some(&HeaderValue::from_static("1")));
+    }
+
+    #[actix_web::test]
+    async fn upstream_connections_are_reused(){
+        let (proxy, accepted) = upstream(|mut stream| async move {
+            while read_head(&mut stream).await {
+                let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: keep-alive, X-Hop\r\nX-Hop: 1\r\n\r\nok";
+                if stream.write_all(response).await.is_err() {
+                    return;
+                }
+            }
+        }).await;
+
+        for _ in 0..2 {
+            let response = forward(&proxy, TestRequest::get().uri("/page")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers().get("x-hop"), None, "Headers listed in the upstream's Connection should be dropped");
+            assert_eq!(to_bytes(response.into_body()).await.unwrap_or_default(), Bytes::from_static(b"ok"));
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 1, "The second request should reuse the first connection");
+    }
+
+    #[actix_web::test]
+    async fn websockets_are_bridged(){
+        let (proxy, _) = upstream(|mut stream| async move {
+            if !read_head(&mut stream).await {
+                return;
+            }
+            let switched = b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n";
+            let mut frames = Vec::new();
+            if stream.write_all(switched).await.is_ok() && stream.read_to_end(&mut frames).await.is_ok() {
+                let _ = stream.write_all(&[b"echo:", frames.as_slice()].concat()).await;
+            }
+        }).await;
+
+        let req = TestRequest::get()
+            .uri("/socket")
+            .insert_header(("Connection", "Upgrade"))
+            .insert_header(("Upgrade", "websocket"))
+            .set_payload("ping");
+        let response = forward(&proxy, req).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers().get(actix_header::UPGRADE).and_then(|value| value.to_str().ok()), Some("websocket"));
+        assert_eq!(to_bytes(response.into_body()).await.unwrap_or_default(), Bytes::from_static(b"echo:ping"), "Client frames should reach the upstream and its answer the client");
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use crate::config::CONFIG;
+use crate::identity::{ClientId, Network};
+use actix_http::{HttpMessage, HttpService, Protocol, Request, Response, body::MessageBody, error::DispatchError};
+use actix_http::header::{HOST, HeaderValue};
+use actix_server::ServerServiceFactory;
+use actix_service::{IntoServiceFactory, Service, ServiceFactory, ServiceFactoryExt, apply_fn_factory, fn_service, map_config};
+use actix_web::dev::AppConfig;
+
+/// First 12 bytes of every PROXY protocol v2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, address family and protocol, then the address block length.
+const HEADER_LEN: usize = 16;
+/// Longest address block read, the addresses plus room for the TLVs Tor and HAProxy send.
+const MAX_BLOCK_LEN: usize = 1024;
+
+const VERSION_2: u8 = 0x20;
+const COMMAND_LOCAL: u8 = 0x00;
+const COMMAND_PROXY: u8 = 0x01;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// Accepts PROXY protocol v2 headers, as sent by `HiddenServiceExportCircuitID haproxy` or HAProxy.
+///
+/// Connections from `trusted_sources` must start with a header, whose source address is then
+/// the peer address of every request on the connection. Other connections are served as they
+/// are, so they can not claim an address.
+pub struct ProxyProtocol {
+    trusted: Vec<Network>,
+}
+
+impl ProxyProtocol {
+    #[must_use]
+    pub fn new() -> Self {
+        Self { trusted: Network::parse_all(&CONFIG.server.proxy_protocol.trusted_sources) }
+    }
+
+    /// Reads the header of `stream` if it comes from a trusted source, returning the stream and
+    /// the address to serve it as.
+    ///
+    /// # Errors
+    /// Will return `Err` if a trusted source does not send a valid header within `timeout_ms`.
+    pub async fn accept(&self, mut stream: TcpStream) -> io::Result<(TcpStream, Option<SocketAddr>)> {
+        let peer = stream.peer_addr().ok();
+        if !peer.is_some_and(|peer| self.trusts(peer.ip())) {
+            return Ok((stream, peer));
+        }
+        let source = timeout(Duration::from_millis(CONFIG.server.proxy_protocol.timeout_ms), read_header(&mut stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY protocol header timed out"))??;
+        Ok((stream, source.or(peer)))
+    }
+
+    /// Whether `ip` must send a header, every source does when none are configured.
+    #[inline]
+    fn trusts(&self, ip: IpAddr) -> bool {
+        let address = ClientId::from_ip(ip).0;
+        self.trusted.is_empty() || self.trusted.iter().any(|network| network.contains(address))
+    }
+}
+
+impl Default for ProxyProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// HTTP/1.1 service for the connections of a listener bound to `local`, which `proxy` accepts
+/// before `app` serves their requests.
+///
+/// `HttpServer` has no hook to read the header before HTTP, so the service is built from its
+/// public parts. Only `HttpServer` can give the application its bound address as connection
+/// config, so requests without a `Host` get that address as theirs instead.
+pub fn http_service<F, I, S, B>(proxy: Arc<ProxyProtocol>, local: SocketAddr, keep_alive: Duration, app: F) -> impl ServerServiceFactory<TcpStream>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error>,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>>,
+    B: MessageBody + 'static,
+{
+    let host = HeaderValue::from_str(&local.to_string()).ok();
+    move || {
+        let proxy = proxy.clone();
+        let host = host.clone();
+        let app = apply_fn_factory(app().into_factory(), move |mut req: Request, app: &S::Service| {
+            if let Some(host) = &host
+                && !req.headers().contains_key(HOST)
+            {
+                req.headers_mut().insert(HOST, host.clone());
+            }
+            app.call(req)
+        })
+            .map_err(|err| err.into().error_response());
+        fn_service(move |stream: TcpStream| {
+            let proxy = proxy.clone();
+            async move {
+                let (stream, peer) = proxy.accept(stream).await.map_err(DispatchError::Io)?;
+                Ok::<_, DispatchError>((stream, Protocol::Http1, peer))
+            }
+        })
+            .and_then(HttpService::build()
+                .keep_alive(keep_alive)
+                .local_addr(local)
+                .finish(map_config(app, |_| AppConfig::default())))
+    }
+}
+
+/// Reads exactly one header, leaving the request that follows it in the stream.
+async fn read_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid PROXY protocol v2 header");
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let len = block_len(&header).ok_or_else(invalid)?;
+    let mut block = vec![0u8; len];
+    stream.read_exact(&mut block).await?;
+    source(&header, &block).ok_or_else(invalid)
+}
+
+/// Length of the address block, `None` if this is not a v2 header.
+fn block_len(header: &[u8; HEADER_LEN]) -> Option<usize> {
+    let (signature, rest) = header.split_first_chunk::<12>()?;
+    let [version_command, _, len_hi, len_lo] = *rest else {
+        return None;
+    };
+    if *signature != SIGNATURE || version_command & 0xf0 != VERSION_2 {
+        return None;
+    }
+    Some(usize::from(u16::from_be_bytes([len_hi, len_lo]))).filter(|len| *len <= MAX_BLOCK_LEN)
+}
+
+/// Source address of the connection, `Some(None)` for health checks and families without one.
+///
+/// Returns `None` if the command is unknown or the block is too short for its family.
+fn source(header: &[u8; HEADER_LEN], block: &[u8]) -> Option<Option<SocketAddr>> {
+    match header[12] & 0x0f {
+        COMMAND_LOCAL => Some(None),
+        COMMAND_PROXY => match header[13] {
+            TCP_OVER_IPV4 => {
+                let ip: [u8; 4] = block.get(..4)?.try_into().ok()?;
+                let port: [u8; 2] = block.get(8..10)?.try_into().ok()?;
+                Some(Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), u16::from_be_bytes(port))))
+            }
+            TCP_OVER_IPV6 => {
+                let ip: [u8; 16] = block.get(..16)?.try_into().ok()?;
+                let port: [u8; 2] = block.get(32..34)?.try_into().ok()?;
+                Some(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), u16::from_be_bytes(port))))
+            }
+            _ => Some(None),
+        },
+        _ => None,
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use tokio::io::AsyncWriteExt;
+    #[allow(unused_imports)]
+    use tokio::net::TcpListener;
+
+    /// Header of a TCP over IPv4 connection from `source`.
+    #[allow(dead_code)]
+    fn ipv4_header(source: SocketAddr) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[VERSION_2 | COMMAND_PROXY, TCP_OVER_IPV4]);
+        header.extend_from_slice(&12u16.to_be_bytes());
+        if let IpAddr::V4(ip) = source.ip() {
+            header.extend_from_slice(&ip.octets());
+        }
+        header.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        header.extend_from_slice(&source.port().to_be_bytes());
+        header.extend_from_slice(&80u16.to_be_bytes());
+        header
+    }
+
+    /// Sends `bytes` over a loopback connection and accepts it with `proxy`, returning the
+    /// address it is served as and what is left in the stream.
+    #[allow(dead_code)]
+    async fn accept(proxy: &ProxyProtocol, bytes: &[u8]) -> io::Result<(Option<SocketAddr>, SocketAddr, Vec<u8>)> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        client.write_all(bytes).await?;
+        client.shutdown().await?;
+        let (stream, _) = listener.accept().await?;
+        let (mut stream, peer) = proxy.accept(stream).await?;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await?;
+        Ok((peer, client.local_addr()?, rest))
+    }
+
+    #[tokio::test]
+    async fn only_trusted_peers_send_a_header(){
+        let source = SocketAddr::from(([203, 0, 113, 7], 4242));
+        let mut bytes = ipv4_header(source);
+        bytes.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let loopback = || Network::parse_all(&["127.0.0.0/8".to_owned()]);
+
+        let trusted = ProxyProtocol { trusted: loopback() };
+        let (peer, _, rest) = accept(&trusted, &bytes).await.unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(peer, Some(source), "A trusted peer should be served as the source of its header");
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n", "The request should be left in the stream");
+
+        let untrusted = ProxyProtocol { trusted: Network::parse_all(&["10.0.0.0/8".to_owned()]) };
+        let (peer, client, rest) = accept(&untrusted, &bytes).await.unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(peer, Some(client), "An untrusted peer should not claim an address");
+        assert_eq!(rest, bytes, "The stream of an untrusted peer should not be read");
+
+        assert!(accept(&trusted, b"GET / HTTP/1.1\r\n\r\n").await.is_err(), "A trusted peer without a header should be refused");
+        let everyone = ProxyProtocol { trusted: Vec::new() };
+        assert_eq!(accept(&everyone, &bytes).await.ok().and_then(|(peer, _, _)| peer), Some(source), "Every peer is trusted when none are configured");
+    }
+
+    /// Serves one connection sending `bytes` with [`http_service`] in front of an application
+    /// answering with the peer address and host of the request, returning the bound address and
+    /// the response.
+    #[allow(dead_code)]
+    async fn serve(bytes: &[u8]) -> io::Result<(SocketAddr, String)> {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let local = listener.local_addr()?;
+        let proxy = Arc::new(ProxyProtocol { trusted: Network::parse_all(&["127.0.0.0/8".to_owned()]) });
+        let app = || actix_web::App::new().default_service(actix_web::web::to(|req: actix_web::HttpRequest| async move {
+            let peer = req.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+            format!("{peer} {}", req.connection_info().host())
+        }));
+        let server = actix_server::Server::build()
+            .workers(1)
+            .listen("test", listener, http_service(proxy, local, Duration::from_secs(5), app))?
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut client = TcpStream::connect(local).await?;
+        client.write_all(bytes).await?;
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        handle.stop(false).await;
+        Ok((local, String::from_utf8_lossy(&response).into_owned()))
+    }
+
+    #[actix_web::test]
+    async fn requests_are_served_as_the_header_source(){
+        let source = SocketAddr::from(([203, 0, 113, 7], 4242));
+        let mut bytes = ipv4_header(source);
+        bytes.extend_from_slice(b"GET / HTTP/1.0\r\n\r\n");
+        let (local, response) = serve(&bytes).await.unwrap_or_else(|e| panic!("{e}"));
+        assert!(response.starts_with("HTTP/1.0 200"), "{response}");
+        assert!(response.ends_with(&format!("{source} {local}")), "Requests without a Host should get the bound address: {response}");
+
+        let mut bytes = ipv4_header(source);
+        bytes.extend_from_slice(b"GET / HTTP/1.0\r\nHost: example.onion\r\n\r\n");
+        let (_, response) = serve(&bytes).await.unwrap_or_else(|e| panic!("{e}"));
+        assert!(response.ends_with(&format!("{source} example.onion")), "{response}");
+    }
+
+    #[test]
+    fn tor_circuit_header(){
+        let mut header = [0u8; HEADER_LEN];
+        header[..12].copy_from_slice(&SIGNATURE);
+        header[12] = VERSION_2 | COMMAND_PROXY;
+        header[13] = TCP_OVER_IPV6;
+        header[14..].copy_from_slice(&36u16.to_be_bytes());
+
+        let circuit = Ipv6Addr::new(0xfc00, 0xdead, 0xbeef, 0x4dad, 0, 0, 0, 0x12d);
+        let mut block = [0u8; 36];
+        block[..16].copy_from_slice(&circuit.octets());
+        block[16..32].copy_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        block[32..34].copy_from_slice(&4242u16.to_be_bytes());
+        block[34..].copy_from_slice(&80u16.to_be_bytes());
+
+        assert_eq!(block_len(&header), Some(36));
+        assert_eq!(source(&header, &block), Some(Some(SocketAddr::new(IpAddr::V6(circuit), 4242))));
+        assert_eq!(source(&header, &block[..20]), None, "A short block should be refused");
+
+        header[12] = VERSION_2 | COMMAND_LOCAL;
+        assert_eq!(source(&header, &[]), Some(None), "Health checks keep the real peer");
+
+        header[0] = b'G';
+        assert_eq!(block_len(&header), None, "Plain HTTP is not a PROXY header");
+    }
+}
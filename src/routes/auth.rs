@@ -1,24 +1,170 @@
-use super::get_circuit_id;
+use super::{challenge::{same_origin_path, serve_challenge_page}, tarpit::{Tarpit, TarpitReason}};
 
-use crate::session::{Session, SessionCache};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use actix_web::{HttpResponse, HttpRequest, Result, web};
+use crate::{
+    challenge_pool::ChallengePool,
+    config::CONFIG,
+    identity::{ClientIdentity, IdentityExtractor},
+    load::LoadMetrics,
+    session::{
+        Session,
+        SessionCache,
+        SessionEntry,
+        ban::BanList,
+        circuit_history::CircuitHistory,
+        issuance::IssuanceLimiter,
+        token::{SessionToken, TokenDenyList}
+    },
+    upstream::UpstreamStatus
+};
+
+use actix_web::{HttpResponse, HttpResponseBuilder, HttpRequest, Result, web};
+use actix_web::http::{Method, StatusCode};
+use tokio::sync::watch::Receiver;
 
 #[cfg(feature = "debug")]
 use tracing::info;
 
+/// Difficulty bits the session solved, for `auth_request_set` in nginx.
+pub const EFFORT_HEADER: &str = "X-Foxyon-Effort";
+/// Seconds since the session was created.
+pub const SESSION_AGE_HEADER: &str = "X-Foxyon-Session-Age";
+/// ID of the session cookie that authenticated the request, to log and revoke it.
+pub const TOKEN_HEADER: &str = "X-Foxyon-Token";
+/// Method of the original request, as sent by Traefik `forwardAuth` and Caddy `forward_auth`.
+const FORWARDED_METHOD_HEADER: &str = "X-Forwarded-Method";
+/// Path and query of the original request, as sent by Traefik `forwardAuth` and Caddy `forward_auth`.
+const FORWARDED_URI_HEADER: &str = "X-Forwarded-Uri";
+
 // Handler for requests validated through Nginx `auth_subrequest`.
 //
-// - Returns HTTP 204 if the session is valid (user already authenticated).
-// - Returns HTTP 401 if the client must solve the PoW challenge.
-pub async fn auth(req: HttpRequest, session: web::Data<SessionCache>) -> Result<HttpResponse> {
-    let circuit_id = get_circuit_id(req.headers().get("X-Circuit-ID"))?;
-    if session.get_ref().contains(circuit_id).await {
+// - Returns HTTP 200 if the session is valid (user already authenticated), with the
+//   `X-Foxyon-Effort` and `X-Foxyon-Session-Age` headers so nginx can pick them up with
+//   `auth_request_set` and route high-effort clients differently. A valid session cookie
+//   is accepted without a lookup, spending the credits kept for its ID, and adds its ID
+//   in `X-Foxyon-Token`.
+// - Returns HTTP 401 if the client must solve the PoW challenge, or has no identity and no
+//   valid session cookie.
+// - Returns HTTP 403 if the circuit is banned for repeatedly failing the challenge. Only in
+//   proxy mode is it tarpitted, a slow subrequest would hold the nginx or Traefik request
+//   open along with it; the challenge routes tarpit banned circuits in every mode.
+pub async fn auth(
+    req: HttpRequest,
+    session: web::Data<SessionCache>,
+    metrics: web::Data<LoadMetrics>,
+    bans: web::Data<BanList>,
+    tarpit: web::Data<Tarpit>,
+    identity: web::Data<IdentityExtractor>,
+    tokens: web::Data<TokenDenyList>) -> Result<HttpResponse>
+{
+    let _in_flight = metrics.track();
+    // A request without an identity can still carry a session cookie.
+    let client_id = identity.identify(&req).ok();
+    if let Some(client_id) = client_id
+        && bans.is_banned(client_id).await
+    {
+        metrics.auth(false);
+        #[cfg(feature = "debug")]
+        info!("Client {client_id} is banned");
+        let tarpitted = if CONFIG.proxy.enabled { tarpit.respond(TarpitReason::Banned) } else { None };
+        return Ok(tarpitted.unwrap_or_else(|| HttpResponse::Forbidden().finish()));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    if CONFIG.session.cookie.enabled
+        && let Some(token) = SessionToken::from_request(&req, now)
+        && !tokens.is_revoked(&token).await
+    {
+        let spent = session.get_ref().spend_token(&token).await;
+        metrics.auth(spent);
+        if !spent {
+            #[cfg(feature = "debug")]
+            info!("Client {client_id:?} is out of session cookie credits");
+            return Ok(HttpResponse::Unauthorized().finish());
+        }
+        #[cfg(feature = "debug")]
+        info!("Client {client_id:?} authenticated by session cookie");
+        return Ok(authenticated(token.entry, now)
+            .insert_header((TOKEN_HEADER, token.id_hex()))
+            .finish());
+    }
+    let entry = match client_id {
+        Some(client_id) => session.get_ref().spend(client_id).await,
+        None => None,
+    };
+    metrics.auth(entry.is_some());
+    if let Some(entry) = entry {
         #[cfg(feature = "debug")]
-        info!("Circuit ID: {circuit_id} authenticated successfully");
-        return Ok(HttpResponse::Ok().finish());
+        info!("Client {client_id:?} authenticated successfully");
+        return Ok(authenticated(entry, now).finish());
     }
     #[cfg(feature = "debug")]
-    info!("Circuit ID: {circuit_id} not authenticated");
+    info!("Client {client_id:?} not authenticated");
     Ok(HttpResponse::Unauthorized().finish())
+}
+
+/// `auth` for Traefik `forwardAuth` and Caddy `forward_auth`, which hand the response to the
+/// client whenever it is not a 2xx.
+///
+/// Clients that must solve the PoW challenge get the challenge page with a 401, so the proxy
+/// serves it on the URL they asked for, and its form returns them to the `X-Forwarded-Uri` once
+/// solved. Requests whose `X-Forwarded-Method` is not `GET` or `HEAD` keep the bare 401, a page
+/// is of no use to them.
+///
+/// # Errors
+/// Will return the errors of `auth` and `challenge_page`.
+#[allow(clippy::too_many_arguments)]
+pub async fn forward_auth(
+    req: HttpRequest,
+    session: web::Data<SessionCache>,
+    metrics: web::Data<LoadMetrics>,
+    bans: web::Data<BanList>,
+    tarpit: web::Data<Tarpit>,
+    identity: web::Data<IdentityExtractor>,
+    tokens: web::Data<TokenDenyList>,
+    difficulty: web::Data<Receiver<u8>>,
+    upstream: web::Data<Receiver<UpstreamStatus>>,
+    history: web::Data<CircuitHistory>,
+    issuance: web::Data<IssuanceLimiter>,
+    pool: web::Data<ChallengePool>) -> Result<HttpResponse>
+{
+    let verdict = auth(req.clone(), session, metrics.clone(), bans.clone(), tarpit.clone(), identity.clone(), tokens).await?;
+    if verdict.status() != StatusCode::UNAUTHORIZED || !wants_page(&req) {
+        return Ok(verdict);
+    }
+    #[cfg(feature = "debug")]
+    info!(
+        uri = ?req.headers().get(FORWARDED_URI_HEADER),
+        host = ?req.headers().get("X-Forwarded-Host"),
+        "Serving the challenge page through forward auth"
+    );
+    let return_to = req.headers()
+        .get(FORWARDED_URI_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(same_origin_path)
+        .map(str::to_owned);
+    let mut page = serve_challenge_page(req, difficulty, metrics, upstream, history, bans, issuance, pool, tarpit, identity, return_to.as_deref()).await?;
+    // A 200 would let the proxy through to the upstream.
+    if page.status() == StatusCode::OK {
+        *page.status_mut() = StatusCode::UNAUTHORIZED;
+    }
+    Ok(page)
+}
+
+/// Whether the original request can show the challenge page, also when its method was not sent.
+fn wants_page(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(FORWARDED_METHOD_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Method>().ok())
+        .is_none_or(|method| method == Method::GET || method == Method::HEAD)
+}
+
+/// 200 with the effort and age headers of `entry`.
+fn authenticated(entry: SessionEntry, now: u64) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((EFFORT_HEADER, itoa::Buffer::new().format(entry.difficulty_bits)))
+        .insert_header((SESSION_AGE_HEADER, itoa::Buffer::new().format(entry.age(now))));
+    response
 }
\ No newline at end of file
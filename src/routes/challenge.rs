@@ -1,21 +1,35 @@
-use super::get_circuit_id;
+use super::tarpit::{Tarpit, TarpitReason};
 
+use std::collections::HashMap;
+use std::sync::LazyLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::{
+    challenge_pool::ChallengePool,
+    config::{CONFIG, OnLimit},
+    identity::{ClientId, ClientIdentity, IdentityExtractor},
+    load::LoadMetrics,
     pow::{
         Challenge,
         CHALLENGE_LEN,
-        B64_LEN,
         MIN_SOLUTION_LEN,
-        check_integrity,
+        issued_at,
+        signed_penalty,
         validate_challenge
     },
     session::{
         SessionCache,
         Session,
-        challenge_blacklist::ChallengeBlacklist
-    }
+        SessionEntry,
+        ban::BanList,
+        challenge_blacklist::ChallengeBlacklist,
+        circuit_history::{CircuitEvent, CircuitHistory},
+        issuance::IssuanceLimiter,
+        token::SessionToken
+    },
+    telemetry::{SolveSample, SolveTelemetry},
+    tor::TorControl,
+    upstream::UpstreamStatus
 };
 
 use actix_web::{
@@ -25,173 +39,441 @@ use actix_web::{
     error,
     web
 };
-use actix_web::error::ErrorInternalServerError;
+use actix_web::cookie::Cookie;
+use actix_web::error::{ErrorInternalServerError, ErrorServiceUnavailable};
 use actix_web::http::header;
 use actix_web::web::Bytes;
+use ada_url::Url;
 use base64_simd::{STANDARD_NO_PAD, Out};
-use sailfish::TemplateOnce;
-use serde::Deserialize;
+use sailfish::{TemplateOnce, runtime::escape::escape_to_string};
 use tracing::error;
 use tracing::debug;
 use tokio::sync::watch::Receiver;
-use memchr::{memchr_iter, memchr};
-use ada_url::Url;
 
-const MAX_SOLUTION_LENGTH: usize = 150;
+const MAX_SOLUTION_LENGTH: usize = 256;
+/// Form action of the challenge template, which the return path of forward auth is added to,
+/// escaped like the template renders the challenge route.
+static FORM_ACTION: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    let mut action = String::from("action=\"");
+    escape_to_string(&CONFIG.routes.challenge, &mut action);
+    action.push('"');
+    action.into_bytes()
+});
+/// Query parameter of the form action holding the path to return to once the challenge is solved.
+const RETURN_PARAM: &str = "return";
+
+/// # Errors
+/// Will return `Err` if there is an error rendering the challenge template,
+/// returning an `InternalServerError` response, `ServiceUnavailable` while
+/// the upstream lockdown is active, or `Forbidden` if the circuit is banned
+#[allow(clippy::too_many_arguments)]
+pub async fn challenge_page(
+    req: HttpRequest,
+    difficulty: web::Data<Receiver<u8>>,
+    metrics: web::Data<LoadMetrics>,
+    upstream: web::Data<Receiver<UpstreamStatus>>,
+    history: web::Data<CircuitHistory>,
+    bans: web::Data<BanList>,
+    issuance: web::Data<IssuanceLimiter>,
+    pool: web::Data<ChallengePool>,
+    tarpit: web::Data<Tarpit>,
+    identity: web::Data<IdentityExtractor>) -> Result<HttpResponse>
+{
+    serve_challenge_page(req, difficulty, metrics, upstream, history, bans, issuance, pool, tarpit, identity, None).await
+}
 
+/// [`challenge_page`] whose form returns the client to `return_to` once solved, for forward auth
+/// where the page is served on the URL the client asked for.
+///
+/// # Errors
+/// Will return the errors of [`challenge_page`].
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn serve_challenge_page(
+    req: HttpRequest,
+    difficulty: web::Data<Receiver<u8>>,
+    metrics: web::Data<LoadMetrics>,
+    upstream: web::Data<Receiver<UpstreamStatus>>,
+    history: web::Data<CircuitHistory>,
+    bans: web::Data<BanList>,
+    issuance: web::Data<IssuanceLimiter>,
+    pool: web::Data<ChallengePool>,
+    tarpit: web::Data<Tarpit>,
+    identity: web::Data<IdentityExtractor>,
+    return_to: Option<&str>) -> Result<HttpResponse>
+{
+    let _in_flight = metrics.track();
+    if upstream.borrow().lockdown {
+        return Err(ErrorServiceUnavailable("Upstream unavailable, try again later"));
+    }
 
-#[derive(Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
-pub struct Solution {
-    solution: String,
+    // In cookie mode a new client gets its identity with the page, so its submissions, failures
+    // and later pages all count against it. The page handing it out is counted against the shared
+    // `UNIDENTIFIED` bucket like a request without an identity, as a fresh ID has no history and
+    // a client dropping its cookie would otherwise start every page with a clean one.
+    let (client_id, cookie) = match identity.assign(&req) {
+        Ok((client_id, cookie)) => (Some(client_id), cookie),
+        Err(_) => (None, None),
+    };
+    let known = client_id.filter(|_| cookie.is_none());
+    let limit_id = known.unwrap_or(ClientId::UNIDENTIFIED);
+    if bans.is_banned(limit_id).await {
+        return tarpit.respond(TarpitReason::Banned).ok_or_else(|| SolutionError::Banned.into());
+    }
+
+    if !issuance.try_issue(limit_id).await {
+        if CONFIG.pow.issuance.on_limit == OnLimit::Reuse
+            && let Some(client_id) = known
+            && let Some(body) = issuance.last_page(client_id).await
+        {
+            return Ok(page_response(body, return_to, cookie));
+        }
+        if let Some(response) = tarpit.respond(TarpitReason::RateLimited) {
+            return Ok(response);
+        }
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, issuance.retry_after()))
+            .finish());
+    }
+    metrics.challenge_issued();
+
+    let mut extra_bits = 0;
+    if CONFIG.pow.circuit_penalty.enabled {
+        extra_bits = history.record(limit_id, CircuitEvent::Fetched).await;
+        metrics.circuit_penalty(extra_bits);
+    }
+
+    // Penalized circuits get a harder challenge than the pooled ones.
+    let pooled = if CONFIG.pow.pool.enabled && extra_bits == 0 {
+        pool.pop(*difficulty.borrow())
+    } else {
+        None
+    };
+
+    let (body, expires_at) = if let Some(page) = pooled {
+        (page.body, page.expires_at)
+    } else {
+        let challenge = Challenge::new(difficulty.as_ref(), extra_bits);
+        let expires_at = challenge.expires_at;
+        let body = challenge.render_once().map_err(|e| {
+            error!(error = ?e, "Failed to render challenge template");
+            ErrorInternalServerError("Failed to render challenge template")
+        })?;
+        (Bytes::from(body), expires_at)
+    };
+
+    if CONFIG.pow.issuance.on_limit == OnLimit::Reuse
+        && let Some(client_id) = client_id
+    {
+        issuance.remember(client_id, body.clone(), expires_at).await;
+    }
+    Ok(page_response(body, return_to, cookie))
 }
 
-const DUMMY_URL: &str = "http://a.b/?";
+/// 200 with the challenge page, setting the identity `cookie` if the client was just assigned one.
+///
+/// The return path is added to the rendered page, so pooled and reused pages carry it too.
+fn page_response(body: Bytes, return_to: Option<&str>, cookie: Option<Cookie<'static>>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/html");
+    if let Some(cookie) = cookie {
+        response.cookie(cookie);
+    }
+    match return_to {
+        Some(path) => response.body(with_return(&body, path)),
+        None => response.body(body),
+    }
+}
 
-/// # Errors
-/// Will return `Err` if there is an error rendering the challenge template,
-/// returning an `InternalServerError` response
-#[allow(clippy::unused_async)]
-pub async fn challenge_page(cpu_usage: web::Data<Receiver<f32>>) -> Result<HttpResponse> {
-    let body = Challenge::new(cpu_usage.as_ref()).render_once().map_err(|e| {
-        error!(error = ?e, "Failed to render challenge template");
-        ErrorInternalServerError("Failed to render challenge template")
-    })?;
-    Ok(HttpResponse::Ok()
-        .content_type("text/html")
-        .body(body))
+/// `body` with its form posting `path` along as the `return` parameter.
+fn with_return(body: &Bytes, path: &str) -> Bytes {
+    let Some(at) = memchr::memmem::find(body, &FORM_ACTION) else {
+        error!("Challenge template has no form action to add the return path to");
+        return body.clone();
+    };
+    let (head, tail) = body.split_at(at.saturating_add(FORM_ACTION.len()).saturating_sub(1));
+    let mut page = Vec::with_capacity(body.len().saturating_add(path.len().saturating_mul(3)).saturating_add(8));
+    page.extend_from_slice(head);
+    page.push(b'?');
+    page.extend_from_slice(RETURN_PARAM.as_bytes());
+    page.push(b'=');
+    for byte in path.bytes() {
+        // Everything but unreserved characters and `/` is escaped, which also keeps the
+        // attribute free of quotes and `&`.
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            page.push(byte);
+        } else {
+            page.extend_from_slice(format!("%{byte:02X}").as_bytes());
+        }
+    }
+    page.extend_from_slice(tail);
+    Bytes::from(page)
+}
+
+/// `path` if it is a path on this origin, which is safe to redirect to.
+///
+/// `//host/...` and `/\host/...` are protocol-relative redirects to another site for browsers.
+pub(super) fn same_origin_path(path: &str) -> Option<&str> {
+    (path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.starts_with("/\\")
+        && !path.chars().any(char::is_control))
+        .then_some(path)
+}
+
+/// The validated `return` parameter of a submission made from a forward auth page.
+fn return_path(req: &HttpRequest) -> Option<String> {
+    web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok()?
+        .into_inner()
+        .remove(RETURN_PARAM)
+        .filter(|path| same_origin_path(path).is_some())
 }
 
 /// # Errors
 /// Will return `Err` if validation fails or does not receive the appropriate header, such as ‘X-circuit-id’.
 /// returning an `ErrorBadRequest` or `ErrorUnauthorized` response
+#[allow(clippy::too_many_arguments)]
 pub async fn challenge_post(
     form: web::Bytes,
     req: HttpRequest,
     session: web::Data<SessionCache>,
-    blacklist: web::Data<ChallengeBlacklist>) -> Result<HttpResponse, error::Error>
+    blacklist: web::Data<ChallengeBlacklist>,
+    metrics: web::Data<LoadMetrics>,
+    upstream: web::Data<Receiver<UpstreamStatus>>,
+    telemetry: web::Data<SolveTelemetry>,
+    history: web::Data<CircuitHistory>,
+    bans: web::Data<BanList>,
+    tarpit: web::Data<Tarpit>,
+    tor: web::Data<TorControl>,
+    identity: web::Data<IdentityExtractor>) -> Result<HttpResponse, error::Error>
 {
+    let _in_flight = metrics.track();
+
+    if upstream.borrow().lockdown {
+        return Err(SolutionError::Lockdown.into());
+    }
 
-    let circuit_id = get_circuit_id(req.headers().get("X-Circuit-Id"))?;
+    // The identity was assigned with the page, a submission without one was not made from it.
+    let client_id = identity.identify(&req)?;
+    if bans.is_banned(client_id).await {
+        return tarpit.respond(TarpitReason::Banned).ok_or_else(|| SolutionError::Banned.into());
+    }
+
+    let result = redeem_solution(&form, client_id, &session, &blacklist, &telemetry).await;
+    if let Some(response) = record_submission(client_id, circuit_event(&result), result.as_ref().err(), &bans, &history, &tarpit, tor).await {
+        return Ok(response);
+    }
+    let entry = result?;
 
-    let (nonce, challenge, difficulty_bits, expires_at, integrity_base64) = validate_and_get_user_input(&form)?;
+    // In forward auth mode the page was served on the URL the client asked for, which the form
+    // carries from `X-Forwarded-Uri`. In proxy mode there is no such header, so it is taken from
+    // the `Referer`, the page the form posts from.
+    let returned = if CONFIG.forward_auth.enabled { return_path(&req) } else { None };
+    let referer = if CONFIG.proxy.enabled { referer_path(&req) } else { None };
+    let original_uri = req.headers()
+        .get("X-Original-URI")
+        .and_then(|v| v.to_str().ok())
+        .or(returned.as_deref())
+        .or(referer.as_deref())
+        .unwrap_or("/");
+
+    #[cfg(feature = "debug")]
+    debug!("Original URI: {}", original_uri);
+
+    let mut response = HttpResponse::SeeOther();
+    response.insert_header((header::LOCATION, original_uri));
+    if CONFIG.session.cookie.enabled {
+        response.cookie(SessionToken::issue(entry).cookie(entry.created_at));
+    }
+    Ok(response.finish())
+}
+
+/// Validates a submitted solution and creates the session of `client_id` if it holds.
+async fn redeem_solution(
+    form: &Bytes,
+    client_id: ClientId,
+    session: &SessionCache,
+    blacklist: &ChallengeBlacklist,
+    telemetry: &SolveTelemetry) -> Result<SessionEntry, SolutionError>
+{
+    let decoded = decode_form(form)?;
+    let UserInput { nonce, challenge, difficulty_bits, expires_at, integrity_base64, solve_ms, hashes } = validate_and_get_user_input(&decoded)?;
 
     let challenge_bytes: [u8; CHALLENGE_LEN] = match challenge.try_into() {
         Ok(bytes) => bytes,
         Err(_) => {
-            return Err(SolutionError::MalformedInput("Challenge contains more bytes than originally sent").into());
+            return Err(SolutionError::MalformedInput("Challenge contains more bytes than originally sent"));
         }
     };
 
     // TODO
     if !blacklist.try_insert(challenge_bytes).await {
-        return Err(SolutionError::Blacklisted.into());
+        return Err(SolutionError::Blacklisted);
     }
 
     let mut client_integrity_buf = [0u8; 32];
-    let client_integrity = STANDARD_NO_PAD.decode(integrity_base64.as_ref(), Out::from_slice(&mut client_integrity_buf))
+    let client_integrity = STANDARD_NO_PAD.decode(integrity_base64, Out::from_slice(&mut client_integrity_buf))
     .map_err(|_| SolutionError::MalformedInput("Base64 validation failed"))?;
 
-    if !check_integrity(challenge, difficulty_bits, expires_at, client_integrity) {
-        return Err(SolutionError::MalformedInput("Integrity check failed").into());
-    }
+    let Some(penalty) = signed_penalty(challenge, difficulty_bits, expires_at, client_integrity) else {
+        return Err(SolutionError::MalformedInput("Integrity check failed"));
+    };
 
     let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(d) => d.as_secs(),
+        Ok(d) => d,
         Err(e) => {
             error!(error = ?e, "System time is before UNIX_EPOCH");
-            return Err(SolutionError::InternalError.into());
+            return Err(SolutionError::InternalError);
         }
     };
 
-    if expires_at < now {
-        return Err(SolutionError::TimedOut.into());
+    if expires_at < now.as_secs() {
+        return Err(SolutionError::TimedOut);
     }
 
-    if validate_challenge(nonce, challenge, difficulty_bits, expires_at) {
-        session.set(circuit_id).await;
+    if !validate_challenge(nonce, challenge, difficulty_bits, expires_at) {
+        return Err(SolutionError::ValidationFailed);
+    }
 
+    // The penalty of the circuit only makes it work harder, it does not buy a better session.
+    let entry = SessionEntry { difficulty_bits: difficulty_bits.saturating_sub(penalty), created_at: now.as_secs() };
+    session.set(client_id, entry).await;
 
-        let original_uri = req.headers()
-            .get("X-Original-URI")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("/");
+    let issued_at_ms = issued_at(difficulty_bits, expires_at).saturating_mul(1000);
+    let server_ms = u64::try_from(now.as_millis()).unwrap_or(u64::MAX).saturating_sub(issued_at_ms);
+    // What the client reports is only logged, the difficulty is estimated from server timing.
+    debug!(difficulty_bits, server_ms, ?solve_ms, ?hashes, "Challenge solved");
+    telemetry.record(SolveSample { difficulty_bits, server_ms });
 
-        #[cfg(feature = "debug")]
-        debug!("Original URI: {}", original_uri);
+    Ok(entry)
+}
 
-        Ok(HttpResponse::SeeOther().insert_header((header::LOCATION, original_uri)).finish())
-    } else {
-        Err(SolutionError::ValidationFailed.into())
-    }
-}
-
-// #[inline]
-// #[allow(clippy::arithmetic_side_effects, clippy::type_complexity)]
-// fn validate_and_get_user_input(solution: &Bytes) -> Result<(&[u8], &[u8], u8, u64, &[u8]), SolutionError> {
-//     const OFFSET: usize = 9;
-//
-//     if solution.len() < OFFSET {
-//         return Err(SolutionError::MalformedInput("Solution too short"));
-//     }
-//
-//     let mut pipes = memchr_iter(b'%', &solution[OFFSET..]);
-//
-//     // índices absolutos
-//     let p1 = pipes.next().ok_or(SolutionError::MalformedInput("Unable to obtain the nonce"))? + OFFSET;
-//     let p2 = pipes.next().ok_or(SolutionError::MalformedInput("Unable to retrieve the challenge"))? + OFFSET;
-//     let p3 = pipes.next().ok_or(SolutionError::MalformedInput("Unable to obtain the difficulty"))? + OFFSET;
-//     let p4 = pipes.next().ok_or(SolutionError::MalformedInput("Data integrity could not be obtained"))? + OFFSET;
-//
-//     // valida limites antes do slicing
-//     if p1+3 > p2 || p2+3 > p3 || p3+3 > p4 || p4+3 > solution.len() {
-//         return Err(SolutionError::MalformedInput("Malformed slices"));
-//     }
-//
-//     let nonce = &solution[OFFSET..p1];
-//     let challenge = &solution[p1+3..p2];
-//     let difficulty_str = &solution[p2+3..p3];
-//     let expires_str = &solution[p3+3..p4];
-//     let integrity_b64 = &solution[p4+3..];
-//
-//     if nonce.is_empty() || challenge.len() != CHALLENGE_LEN {
-//         return Err(SolutionError::MalformedInput("Validation failed"));
-//     }
-//
-//     let difficulty_bits = atoi_simd::parse::<u8>(difficulty_str)
-//         .map_err(|_| SolutionError::MalformedInput("atoi difficulty failed"))?;
-//     let expires_at = atoi_simd::parse::<u64>(expires_str)
-//         .map_err(|_| SolutionError::MalformedInput("atoi expires_at failed"))?;
-//
-//     Ok((nonce, challenge, difficulty_bits, expires_at, integrity_b64))
-// }
+/// Counts a submission of `client_id` toward its ban and circuit history, closing its circuit
+/// once it is banned, and returns the tarpit response of a tarpitted `error`.
+pub(super) async fn record_submission(
+    client_id: ClientId,
+    event: Option<CircuitEvent>,
+    error: Option<&SolutionError>,
+    bans: &BanList,
+    history: &CircuitHistory,
+    tarpit: &Tarpit,
+    tor: web::Data<TorControl>) -> Option<HttpResponse>
+{
+    if matches!(event, Some(CircuitEvent::Failed | CircuitEvent::Replayed))
+        && bans.failed(client_id).await
+    {
+        // Closing the circuit should not hold the rejection back.
+        let tor = tor.into_inner();
+        actix_web::rt::spawn(async move { tor.close_banned(client_id).await });
+    }
+    if CONFIG.pow.circuit_penalty.enabled
+        && let Some(event) = event
+    {
+        history.record(client_id, event).await;
+    }
+    tarpit.respond(tarpit_reason(error?)?)
+}
+
+/// What a submission outcome says about the circuit that sent it.
+pub(super) fn circuit_event(result: &Result<SessionEntry, SolutionError>) -> Option<CircuitEvent> {
+    match result {
+        Ok(_) => Some(CircuitEvent::Solved),
+        Err(SolutionError::Blacklisted) => Some(CircuitEvent::Replayed),
+        Err(SolutionError::MalformedInput(_) | SolutionError::ValidationFailed) => Some(CircuitEvent::Failed),
+        Err(_) => None,
+    }
+}
+
+/// Path and query of the `Referer`, `None` if there is none or it would leave this origin.
+fn referer_path(req: &HttpRequest) -> Option<String> {
+    let referer = req.headers().get(header::REFERER)?.to_str().ok()?;
+    let url = Url::parse(referer, None).ok()?;
+    same_origin_path(url.pathname()).map(|path| format!("{path}{}", url.search()))
+}
+
+/// Tarpit reason of a rejected submission, if any.
+fn tarpit_reason(err: &SolutionError) -> Option<TarpitReason> {
+    match err {
+        SolutionError::Blacklisted => Some(TarpitReason::Replayed),
+        SolutionError::MalformedInput(_) => Some(TarpitReason::Malformed),
+        SolutionError::ValidationFailed => Some(TarpitReason::Failed),
+        _ => None,
+    }
+}
 
+/// Fields of a submitted solution: `nonce|challenge|difficulty|expires_at|integrity[|solve_ms|hashes]`.
+///
+/// The trailing telemetry fields are optional, solutions pasted from the `<noscript>` solver omit them.
+pub(super) struct UserInput<'a> {
+    pub(super) nonce: &'a [u8],
+    pub(super) challenge: &'a [u8],
+    pub(super) difficulty_bits: u8,
+    pub(super) expires_at: u64,
+    pub(super) integrity_base64: &'a [u8],
+    pub(super) solve_ms: Option<u64>,
+    pub(super) hashes: Option<u64>,
+}
+
+/// Percent-decodes the single `solution` field of the `application/x-www-form-urlencoded` body.
 #[inline]
-#[allow(clippy::arithmetic_side_effects, clippy::type_complexity)]
-fn validate_and_get_user_input(solution: &Bytes) -> Result<(&[u8], &[u8], u8, u64, &[u8]), SolutionError> {
-    if  solution.len() <= MIN_SOLUTION_LEN
-        || memchr::memchr(b'\x80', solution).is_some()
-        || !solution.starts_with(b"solution=") {
+pub(super) fn decode_form(form: &Bytes) -> Result<Vec<u8>, SolutionError> {
+    if  form.len() <= MIN_SOLUTION_LEN
+        || form.len() > MAX_SOLUTION_LENGTH
+        || !form.is_ascii() {
         return Err(SolutionError::MalformedInput("Field validation failure"));
     }
+    let encoded = form.strip_prefix(b"solution=").ok_or(SolutionError::MalformedInput("Field validation failure"))?;
 
-    // SAFETY: Validation is performed at the beginning of the function; we do not need to validate again.
-    let query_str = unsafe {std::str::from_utf8_unchecked(solution)};
+    let hex = |b: Option<&u8>| b.and_then(|b| char::from(*b).to_digit(16)).and_then(|d| u8::try_from(d).ok());
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hi = hex(bytes.next()).ok_or(SolutionError::MalformedInput("Invalid percent-encoding"))?;
+                let lo = hex(bytes.next()).ok_or(SolutionError::MalformedInput("Invalid percent-encoding"))?;
+                decoded.push(hi.wrapping_shl(4) | lo);
+            }
+            b'+' => decoded.push(b' '),
+            b'&' => return Err(SolutionError::MalformedInput("Unexpected form field")),
+            _ => decoded.push(*b),
+        }
+    }
+    Ok(decoded)
+}
 
-    let url = Url::parse(DUMMY_URL, Some(query_str)).map_err(|_| SolutionError::MalformedInput(""))?;
+#[inline]
+pub(super) fn validate_and_get_user_input(solution: &[u8]) -> Result<UserInput<'_>, SolutionError> {
+    let mut fields = solution.split(|b| *b == b'|');
 
+    let nonce = fields.next().filter(|n| !n.is_empty())
+        .ok_or(SolutionError::MalformedInput("Unable to obtain the nonce"))?;
+    let challenge = fields.next().filter(|c| c.len() == CHALLENGE_LEN)
+        .ok_or(SolutionError::MalformedInput("Unable to retrieve the challenge"))?;
+    let difficulty_bits = fields.next().and_then(|d| atoi_simd::parse::<u8>(d).ok())
+        .ok_or(SolutionError::MalformedInput("Unable to obtain the difficulty"))?;
+    let expires_at = fields.next().and_then(|e| atoi_simd::parse::<u64>(e).ok())
+        .ok_or(SolutionError::MalformedInput("Unable to obtain the expiration"))?;
+    let integrity_base64 = fields.next().filter(|i| !i.is_empty())
+        .ok_or(SolutionError::MalformedInput("Data integrity could not be obtained"))?;
+    let solve_ms = fields.next().and_then(|t| atoi_simd::parse::<u64>(t).ok());
+    let hashes = fields.next().and_then(|h| atoi_simd::parse::<u64>(h).ok());
 
+    if fields.next().is_some() {
+        return Err(SolutionError::MalformedInput("Too many fields"));
+    }
 
+    Ok(UserInput { nonce, challenge, difficulty_bits, expires_at, integrity_base64, solve_ms, hashes })
 }
 
 #[derive(Debug, Clone, PartialEq)]
-enum SolutionError {
+pub(super) enum SolutionError {
     MalformedInput(&'static str),
     ValidationFailed,
     Blacklisted,
-    CircuitIdError,
     InternalError,
     TimedOut,
+    Lockdown,
+    Banned,
 }
 
 impl From<SolutionError> for actix_web::Error {
@@ -206,15 +488,103 @@ impl From<SolutionError> for actix_web::Error {
             SolutionError::Blacklisted => {
                 error::ErrorForbidden("Blacklisted challenge")
             }
-            SolutionError::CircuitIdError => {
-                error::ErrorForbidden("CircuitID already in use")
-            }
             SolutionError::InternalError => {
                 error::ErrorInternalServerError("Internal error")
             }
             SolutionError::TimedOut => {
                 error::ErrorForbidden("The challenge has expired!")
             }
+            SolutionError::Lockdown => {
+                error::ErrorServiceUnavailable("Upstream unavailable, no new sessions are being issued")
+            }
+            SolutionError::Banned => {
+                error::ErrorForbidden("Too many failed attempts, try again later")
+            }
         }
     }
-}
\ No newline at end of file
+}
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    const INTEGRITY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+    #[test]
+    fn form_is_percent_decoded(){
+        let form = Bytes::from(format!("solution=123456%7CaB3dE5gH7jK9%7C20%7C1700000000%7C{INTEGRITY}%7C850%7C1048576"));
+        let decoded = decode_form(&form);
+        assert_eq!(decoded, Ok(format!("123456|aB3dE5gH7jK9|20|1700000000|{INTEGRITY}|850|1048576").into_bytes()));
+
+        let input = decoded.as_deref().map_err(Clone::clone).and_then(validate_and_get_user_input);
+        assert!(input.is_ok_and(|input| input.nonce == b"123456"
+            && input.challenge == b"aB3dE5gH7jK9"
+            && input.difficulty_bits == 20
+            && input.expires_at == 1_700_000_000
+            && input.integrity_base64 == INTEGRITY.as_bytes()
+            && input.solve_ms == Some(850)
+            && input.hashes == Some(1_048_576)));
+    }
+
+    #[test]
+    fn return_path_is_added_to_the_form(){
+        let page = Bytes::from_static(b"<form method=\"post\" action=\"/challenge\">");
+        assert_eq!(with_return(&page, "/wiki/Main Page?a=1&b=\"2\""), Bytes::from_static(b"<form method=\"post\" action=\"/challenge?return=/wiki/Main%20Page%3Fa%3D1%26b%3D%222%22\">"));
+        let other = Bytes::from_static(b"<form>");
+        assert_eq!(with_return(&other, "/"), other, "A page without the form action should be left alone");
+    }
+
+    #[test]
+    fn return_path_stays_on_this_origin(){
+        let submitted = |query: &str| return_path(&actix_web::test::TestRequest::post().uri(&format!("/challenge?{query}")).to_http_request());
+        assert_eq!(submitted("return=/wiki/Main%20Page%3Fa%3D1%26b%3D2"), Some("/wiki/Main Page?a=1&b=2".to_owned()));
+        assert_eq!(submitted("return=//evil.example/"), None, "Protocol-relative URLs leave the origin");
+        assert_eq!(submitted("return=/%5Cevil.example/"), None, "Browsers treat a backslash as a slash");
+        assert_eq!(submitted("return=https://evil.example/"), None);
+        assert_eq!(submitted("return=/a%0D%0ASet-Cookie:%20x"), None, "Control characters can not reach the Location header");
+        assert_eq!(submitted(""), None);
+    }
+
+    #[test]
+    fn rendered_page_has_the_form_action(){
+        let page = Challenge::with_bits(20).render_once().unwrap_or_default();
+        assert!(memchr::memmem::find(page.as_bytes(), &FORM_ACTION).is_some(), "The template and FORM_ACTION should agree");
+    }
+
+    #[test]
+    fn malformed_forms_are_refused(){
+        let oversize = Bytes::from(format!("solution={}", "1".repeat(MAX_SOLUTION_LENGTH)));
+        assert_eq!(decode_form(&oversize), Err(SolutionError::MalformedInput("Field validation failure")));
+
+        let other_field = Bytes::from(format!("nonce=123456%7CaB3dE5gH7jK9%7C20%7C1700000000%7C{INTEGRITY}"));
+        assert_eq!(decode_form(&other_field), Err(SolutionError::MalformedInput("Field validation failure")));
+
+        let truncated_escape = Bytes::from(format!("solution=123456%7CaB3dE5gH7jK9%7C20%7C1700000000%7C{INTEGRITY}%7"));
+        assert_eq!(decode_form(&truncated_escape), Err(SolutionError::MalformedInput("Invalid percent-encoding")));
+
+        let extra_field = Bytes::from(format!("solution=123456%7CaB3dE5gH7jK9%7C20%7C1700000000%7C{INTEGRITY}&x=1"));
+        assert_eq!(decode_form(&extra_field), Err(SolutionError::MalformedInput("Unexpected form field")));
+    }
+
+    #[test]
+    fn solution_fields_are_validated(){
+        let missing = b"123456|aB3dE5gH7jK9|20|1700000000";
+        assert!(validate_and_get_user_input(missing).is_err_and(|e| e == SolutionError::MalformedInput("Data integrity could not be obtained")));
+
+        let difficulty = format!("123456|aB3dE5gH7jK9|twenty|1700000000|{INTEGRITY}");
+        assert!(validate_and_get_user_input(difficulty.as_bytes()).is_err_and(|e| e == SolutionError::MalformedInput("Unable to obtain the difficulty")));
+
+        let expiry = format!("123456|aB3dE5gH7jK9|20|soon|{INTEGRITY}");
+        assert!(validate_and_get_user_input(expiry.as_bytes()).is_err_and(|e| e == SolutionError::MalformedInput("Unable to obtain the expiration")));
+
+        let short_challenge = format!("123456|aB3dE5|20|1700000000|{INTEGRITY}");
+        assert!(validate_and_get_user_input(short_challenge.as_bytes()).is_err_and(|e| e == SolutionError::MalformedInput("Unable to retrieve the challenge")));
+
+        let too_many = format!("123456|aB3dE5gH7jK9|20|1700000000|{INTEGRITY}|850|1048576|1");
+        assert!(validate_and_get_user_input(too_many.as_bytes()).is_err_and(|e| e == SolutionError::MalformedInput("Too many fields")));
+
+        // Telemetry is optional, unparsable values are dropped instead of refusing the solution.
+        let telemetry = format!("123456|aB3dE5gH7jK9|20|1700000000|{INTEGRITY}|fast|many");
+        assert!(validate_and_get_user_input(telemetry.as_bytes()).is_ok_and(|input| input.solve_ms.is_none() && input.hashes.is_none()));
+    }
+}
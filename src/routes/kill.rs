@@ -0,0 +1,238 @@
+use std::net::Ipv6Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    identity::ClientId,
+    config::CONFIG,
+    session::{Session, SessionCache, token::TokenDenyList},
+    tor::TorControl
+};
+
+use actix_web::{HttpResponse, HttpRequest, Result, error, web};
+use actix_web::http::header;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tracing::{error, warn};
+
+/// Sessions to kill, exactly one of `circuit`, `client`, `all`, `created_after` and `token` must be given.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct KillQuery {
+    /// `X-Circuit-Id` address of the circuit whose session is killed.
+    circuit: Option<String>,
+    /// Client ID whose session is killed, in any identity mode.
+    client: Option<String>,
+    /// Kills every session.
+    #[serde(default)]
+    all: bool,
+    /// Kills every session created after this Unix timestamp.
+    created_after: Option<u64>,
+    /// Revokes the session cookie with this token ID, as sent in `X-Foxyon-Token`.
+    token: Option<String>,
+    /// With `circuit`, also tears the circuit down through the Tor control port.
+    #[serde(default)]
+    close: bool,
+}
+
+/// Session revocation endpoint, authenticated with `Authorization: Bearer <kill_token>`.
+///
+/// - `?circuit=<X-Circuit-Id>` kills the session of one circuit, 404 if it had none.
+///   With `&close=true` the circuit is also closed through the Tor control port, 502 if that fails.
+/// - `?client=<client id>` kills the session of one client of any identity mode, 404 if it had
+///   none. The ID is taken as 32 hex digits, or in the address form the logs print it in.
+/// - `?all=true` kills every session.
+/// - `?created_after=<unix timestamp>` kills every session created after it, e.g. after an incident.
+/// - `?token=<token id>` revokes one session cookie.
+///
+/// `all` and `created_after` also revoke the session cookies created in their range. `circuit`
+/// and `client` only kill the session looked up by identity: a session cookie handed to the
+/// client stays valid until it expires, as it is not tied to an identity, and is revoked with
+/// `token`, the ID `auth` sends in `X-Foxyon-Token`.
+///
+/// # Errors
+/// Will return `ErrorUnauthorized` if the token is missing or wrong, and `ErrorBadRequest`
+/// if the query does not select exactly one set of sessions, `ErrorBadGateway` if closing the circuit fails.
+pub async fn kill(
+    req: HttpRequest,
+    query: web::Query<KillQuery>,
+    session: web::Data<SessionCache>,
+    tor: web::Data<TorControl>,
+    tokens: web::Data<TokenDenyList>) -> Result<HttpResponse>
+{
+    if !authorized(&req, CONFIG.security.kill_token.as_bytes()) {
+        return Err(error::ErrorUnauthorized("Invalid kill token"));
+    }
+    revoke(query.into_inner(), &session, &tor, &tokens).await
+}
+
+/// Kills the sessions `query` selects, see [`kill`].
+async fn revoke(query: KillQuery, session: &SessionCache, tor: &TorControl, tokens: &TokenDenyList) -> Result<HttpResponse> {
+    match query {
+        KillQuery { circuit: None, client: Some(client), all: false, created_after: None, token: None, close: false } => {
+            let client_id = parse_client(&client).ok_or_else(|| error::ErrorBadRequest("Invalid client ID"))?;
+            if !session.remove(client_id).await {
+                return Ok(HttpResponse::NotFound().finish());
+            }
+            warn!(%client_id, "Session killed");
+        }
+        KillQuery { circuit: Some(address), client: None, all: false, created_after: None, token: None, close } => {
+            let circuit_id = ClientId::from_circuit(&address, None).ok_or_else(|| error::ErrorBadRequest("Invalid circuit address"))?;
+            let removed = session.remove(circuit_id).await;
+            if removed {
+                warn!(%circuit_id, "Session killed");
+            }
+            if close {
+                if let Err(e) = tor.close_circuit(circuit_id).await {
+                    error!(error = ?e, %circuit_id, "Failed to close circuit");
+                    return Err(error::ErrorBadGateway("Failed to close the circuit"));
+                }
+                warn!(%circuit_id, "Circuit closed");
+            } else if !removed {
+                return Ok(HttpResponse::NotFound().finish());
+            }
+        }
+        KillQuery { circuit: None, client: None, all: true, created_after: None, token: None, close: false } => {
+            session.clear().await;
+            tokens.revoke_created_after(0, now()).await;
+            warn!("All sessions killed");
+        }
+        KillQuery { circuit: None, client: None, all: false, created_after: Some(timestamp), token: None, close: false } => {
+            session.remove_created_after(timestamp).await;
+            tokens.revoke_created_after(timestamp, now()).await;
+            warn!(created_after = timestamp, "Sessions killed");
+        }
+        KillQuery { circuit: None, client: None, all: false, created_after: None, token: Some(token), close: false } => {
+            let token_id = u128::from_str_radix(&token, 16).map_err(|_| error::ErrorBadRequest("Invalid token ID"))?;
+            tokens.deny(token_id).await;
+            warn!(%token, "Session cookie revoked");
+        }
+        _ => return Err(error::ErrorBadRequest("Expected exactly one of circuit, client, all, created_after or token")),
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Client ID of `value`, as 32 hex digits or as the IPv6 address [`ClientId`] displays as.
+fn parse_client(value: &str) -> Option<ClientId> {
+    if value.len() == 32 {
+        return u128::from_str_radix(value, 16).ok().map(ClientId);
+    }
+    value.parse::<Ipv6Addr>().ok().map(|address| ClientId(u128::from(address)))
+}
+
+/// Current Unix timestamp, 0 if the system clock is before the epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Compares the bearer token against `expected` in constant time; an empty token never matches.
+fn authorized(req: &HttpRequest, expected: &[u8]) -> bool {
+    if expected.is_empty() {
+        return false;
+    }
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|token| token.ct_eq(expected).into())
+}
+
+#[cfg(feature = "local")]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::session::{SessionEntry, token::SessionToken};
+    #[allow(unused_imports)]
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    #[allow(dead_code)]
+    const CIRCUIT: &str = "fc00:dead:beef:4dad::12d";
+
+    #[allow(dead_code)]
+    fn query(query: &str) -> KillQuery {
+        web::Query::<KillQuery>::from_query(query).map(web::Query::into_inner)
+            .unwrap_or_else(|e| panic!("{query}: {e}"))
+    }
+
+    #[allow(dead_code)]
+    fn token(id: u128, created_at: u64) -> SessionToken {
+        SessionToken { id, entry: SessionEntry { difficulty_bits: 20, created_at }, expires_at: created_at.saturating_add(600) }
+    }
+
+    #[allow(dead_code)]
+    async fn status(kill: &str, session: &SessionCache, tokens: &TokenDenyList) -> StatusCode {
+        match revoke(query(kill), session, &TorControl::default(), tokens).await {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn bearer_token_is_required(){
+        let bearer = |value: &str| TestRequest::default().insert_header((header::AUTHORIZATION, value)).to_http_request();
+        assert!(authorized(&bearer("Bearer secret"), b"secret"));
+        assert!(!authorized(&bearer("Bearer wrong"), b"secret"));
+        assert!(!authorized(&bearer("secret"), b"secret"), "The token should be sent as a bearer token");
+        assert!(!authorized(&TestRequest::default().to_http_request(), b"secret"));
+        assert!(!authorized(&bearer("Bearer "), b""), "An empty kill token should disable the endpoint");
+    }
+
+    #[tokio::test]
+    async fn exactly_one_scope_is_accepted(){
+        let (session, tokens) = (SessionCache::default(), TokenDenyList::default());
+        for kill in ["", "all=true&created_after=100", &format!("circuit={CIRCUIT}&all=true"), "all=true&close=true", "token=ab&created_after=1"] {
+            assert_eq!(status(kill, &session, &tokens).await, StatusCode::BAD_REQUEST, "{kill:?} should be refused");
+        }
+        assert_eq!(status("circuit=2001:db8::1", &session, &tokens).await, StatusCode::BAD_REQUEST, "Addresses outside the circuit network should be refused");
+        assert_eq!(status("token=xyz", &session, &tokens).await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("client=xyz", &session, &tokens).await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("client=::1&close=true", &session, &tokens).await, StatusCode::BAD_REQUEST, "Only circuits can be closed");
+    }
+
+    #[test]
+    fn client_ids_are_parsed_in_both_forms(){
+        let client_id = ClientId(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        assert_eq!(parse_client("0123456789abcdef0123456789abcdef"), Some(client_id));
+        assert_eq!(parse_client(&client_id.to_string()), Some(client_id));
+        assert_eq!(parse_client("192.0.2.1"), None, "IPv4 clients are displayed mapped into IPv6");
+        let mapped = ClientId::from_ip(std::net::Ipv4Addr::new(192, 0, 2, 1).into());
+        assert_eq!(parse_client(&mapped.to_string()), Some(mapped));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_killed(){
+        let (session, tokens) = (SessionCache::default(), TokenDenyList::default());
+        let circuit = |n: u16| ClientId::from_circuit(&format!("fc00:dead:beef:4dad::{n:x}"), None).unwrap_or(ClientId::UNIDENTIFIED);
+        for (n, created_at) in [(1, 100), (2, 200), (3, 300)] {
+            session.set(circuit(n), SessionEntry { difficulty_bits: 20, created_at }).await;
+        }
+
+        assert_eq!(status("circuit=fc00:dead:beef:4dad::1", &session, &tokens).await, StatusCode::NO_CONTENT);
+        assert!(!session.contains(circuit(1)).await);
+        assert_eq!(status("circuit=fc00:dead:beef:4dad::1", &session, &tokens).await, StatusCode::NOT_FOUND, "A circuit without a session should be reported");
+
+        let client = ClientId(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        session.set(client, SessionEntry { difficulty_bits: 20, created_at: 100 }).await;
+        assert_eq!(status("client=0123456789abcdef0123456789abcdef", &session, &tokens).await, StatusCode::NO_CONTENT);
+        assert!(!session.contains(client).await, "Sessions of any identity mode should be killed by client ID");
+        assert_eq!(status("client=0123456789abcdef0123456789abcdef", &session, &tokens).await, StatusCode::NOT_FOUND);
+
+        assert_eq!(status("created_after=250", &session, &tokens).await, StatusCode::NO_CONTENT);
+        session.cache.run_pending_tasks().await;
+        assert!(session.contains(circuit(2)).await, "Older sessions should be kept");
+        assert!(!session.contains(circuit(3)).await);
+        assert!(tokens.is_revoked(&token(3, 300)).await, "Cookies created in the range should be revoked too");
+        assert!(!tokens.is_revoked(&token(2, 200)).await);
+
+        assert_eq!(status("all=true", &session, &tokens).await, StatusCode::NO_CONTENT);
+        assert!(!session.contains(circuit(2)).await);
+        assert!(tokens.is_revoked(&token(2, 200)).await);
+        assert!(tokens.is_revoked(&token(3, 300)).await, "A later kill should not bring revoked cookies back");
+
+        let later = now().saturating_add(60);
+        assert!(!tokens.is_revoked(&token(0xab, later)).await, "Cookies issued after the kill should be accepted");
+        assert_eq!(status("token=00ab", &session, &tokens).await, StatusCode::NO_CONTENT);
+        assert!(tokens.is_revoked(&token(0xab, later)).await);
+        assert!(!tokens.is_revoked(&token(0xac, later)).await);
+    }
+}
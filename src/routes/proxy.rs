@@ -0,0 +1,55 @@
+use super::{auth::auth, challenge::challenge_page, tarpit::Tarpit};
+
+use crate::{
+    challenge_pool::ChallengePool,
+    identity::IdentityExtractor,
+    load::LoadMetrics,
+    proxy::ReverseProxy,
+    session::{
+        SessionCache,
+        ban::BanList,
+        circuit_history::CircuitHistory,
+        issuance::IssuanceLimiter,
+        token::TokenDenyList
+    },
+    upstream::UpstreamStatus
+};
+
+use actix_web::{HttpResponse, HttpRequest, Result, web};
+use actix_web::http::StatusCode;
+use tokio::sync::watch::Receiver;
+
+/// Default service in proxy mode, standing in for nginx `auth_request` and `proxy_pass`.
+///
+/// - Forwards the request to the upstream if `auth` accepts it, with its session headers.
+/// - Answers with the challenge page if the client must solve the PoW challenge, so the
+///   page is served on the URL it asked for and the solution redirects back to it.
+/// - Returns any other verdict of `auth` as is, e.g. 403 for banned clients.
+///
+/// # Errors
+/// Will return the errors of `auth` and `challenge_page`, and `ErrorBadGateway` if the
+/// upstream can not be reached.
+#[allow(clippy::too_many_arguments)]
+pub async fn proxy(
+    req: HttpRequest,
+    payload: web::Payload,
+    session: web::Data<SessionCache>,
+    metrics: web::Data<LoadMetrics>,
+    bans: web::Data<BanList>,
+    tarpit: web::Data<Tarpit>,
+    identity: web::Data<IdentityExtractor>,
+    tokens: web::Data<TokenDenyList>,
+    difficulty: web::Data<Receiver<u8>>,
+    upstream: web::Data<Receiver<UpstreamStatus>>,
+    history: web::Data<CircuitHistory>,
+    issuance: web::Data<IssuanceLimiter>,
+    pool: web::Data<ChallengePool>,
+    reverse_proxy: web::Data<ReverseProxy>) -> Result<HttpResponse>
+{
+    let verdict = auth(req.clone(), session, metrics.clone(), bans.clone(), tarpit.clone(), identity.clone(), tokens).await?;
+    match verdict.status() {
+        StatusCode::OK => reverse_proxy.forward(&req, payload, verdict.headers()).await,
+        StatusCode::UNAUTHORIZED => challenge_page(req, difficulty, metrics, upstream, history, bans, issuance, pool, tarpit, identity).await,
+        _ => Ok(verdict),
+    }
+}
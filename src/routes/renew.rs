@@ -0,0 +1,193 @@
+use super::challenge::{SolutionError, UserInput, circuit_event, decode_form, record_submission, validate_and_get_user_input};
+use super::tarpit::{Tarpit, TarpitReason};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    config::CONFIG,
+    identity::{ClientId, ClientIdentity, IdentityExtractor},
+    load::LoadMetrics,
+    pow::{
+        Challenge,
+        CHALLENGE_LEN,
+        check_renewal_integrity,
+        validate_challenge
+    },
+    session::{
+        SessionCache,
+        Session,
+        SessionEntry,
+        ban::BanList,
+        challenge_blacklist::ChallengeBlacklist,
+        circuit_history::{CircuitEvent, CircuitHistory}
+    },
+    tor::TorControl,
+    upstream::UpstreamStatus
+};
+
+use actix_web::{
+    HttpResponse,
+    HttpRequest,
+    Result,
+    error,
+    web
+};
+use base64_simd::{STANDARD_NO_PAD, Out};
+use tokio::sync::watch::Receiver;
+use tracing::error;
+#[cfg(feature = "debug")]
+use tracing::info;
+
+/// Issues a lighter challenge to a circuit that already holds a session.
+///
+/// The body is `challenge|difficulty|integrity|expires_at`, the same layout as the
+/// challenge page, so the page worker can solve it in the background.
+///
+/// # Errors
+/// Will return `ErrorUnauthorized` if the circuit has no session to renew, `ServiceUnavailable`
+/// while the upstream lockdown is active, or `Forbidden` if the circuit is banned.
+#[allow(clippy::too_many_arguments)]
+pub async fn renew_page(
+    req: HttpRequest,
+    session: web::Data<SessionCache>,
+    difficulty: web::Data<Receiver<u8>>,
+    metrics: web::Data<LoadMetrics>,
+    upstream: web::Data<Receiver<UpstreamStatus>>,
+    bans: web::Data<BanList>,
+    tarpit: web::Data<Tarpit>,
+    identity: web::Data<IdentityExtractor>) -> Result<HttpResponse>
+{
+    let _in_flight = metrics.track();
+    if upstream.borrow().lockdown {
+        return Err(SolutionError::Lockdown.into());
+    }
+    let client_id = identity.identify(&req)?;
+    if bans.is_banned(client_id).await {
+        return tarpit.respond(TarpitReason::Banned).ok_or_else(|| SolutionError::Banned.into());
+    }
+    if !session.contains(client_id).await {
+        return Err(error::ErrorUnauthorized("No session to renew"));
+    }
+
+    let difficulty_bits = renewal_bits(*difficulty.borrow());
+    let challenge = Challenge::renewal(difficulty_bits, client_id);
+
+    #[cfg(feature = "debug")]
+    info!("Client {client_id} renewal challenge with {difficulty_bits} bits");
+
+    let body = [
+        challenge.challenge_str(),
+        itoa::Buffer::new().format(challenge.difficulty_bits),
+        challenge.integrity_b64_str(),
+        itoa::Buffer::new().format(challenge.expires_at),
+    ].join("|");
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(body))
+}
+
+/// Extends the session of the circuit when its renewal challenge is solved.
+///
+/// Failed and replayed renewals count toward the ban and circuit history of the circuit like
+/// those of the challenge page.
+///
+/// # Errors
+/// Will return `Err` if the circuit has no session, is banned, the upstream lockdown is active,
+/// or if the solution fails validation.
+#[allow(clippy::too_many_arguments)]
+pub async fn renew_post(
+    form: web::Bytes,
+    req: HttpRequest,
+    session: web::Data<SessionCache>,
+    blacklist: web::Data<ChallengeBlacklist>,
+    metrics: web::Data<LoadMetrics>,
+    upstream: web::Data<Receiver<UpstreamStatus>>,
+    history: web::Data<CircuitHistory>,
+    bans: web::Data<BanList>,
+    tarpit: web::Data<Tarpit>,
+    tor: web::Data<TorControl>,
+    identity: web::Data<IdentityExtractor>) -> Result<HttpResponse, error::Error>
+{
+    let _in_flight = metrics.track();
+    if upstream.borrow().lockdown {
+        return Err(SolutionError::Lockdown.into());
+    }
+    let client_id = identity.identify(&req)?;
+    if bans.is_banned(client_id).await {
+        return tarpit.respond(TarpitReason::Banned).ok_or_else(|| SolutionError::Banned.into());
+    }
+
+    let Some(current) = session.get(client_id).await else {
+        return Err(error::ErrorUnauthorized("No session to renew"));
+    };
+
+    let result = redeem_renewal(&form, client_id, current, &blacklist).await;
+    // A renewal is not a new session, only its failures say something about the circuit.
+    let event = circuit_event(&result).filter(|event| *event != CircuitEvent::Solved);
+    if let Some(response) = record_submission(client_id, event, result.as_ref().err(), &bans, &history, &tarpit, tor).await {
+        return Ok(response);
+    }
+    let renewed = result?;
+
+    // Renewing restarts the session lifetime while keeping its age and the credits it has left.
+    if !session.renew(client_id, renewed).await {
+        return Err(error::ErrorUnauthorized("No session to renew"));
+    }
+
+    #[cfg(feature = "debug")]
+    info!("Client {client_id} session renewed");
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Validates a submitted renewal of the `current` session of `client_id`, returning the renewed entry.
+///
+/// The effort is capped at the renewal difficulty, so a session earned under heavy load is not
+/// kept at that effort by cheaper renewals.
+async fn redeem_renewal(form: &web::Bytes, client_id: ClientId, current: SessionEntry, blacklist: &ChallengeBlacklist) -> Result<SessionEntry, SolutionError> {
+    let decoded = decode_form(form)?;
+    let UserInput { nonce, challenge, difficulty_bits, expires_at, integrity_base64, .. } = validate_and_get_user_input(&decoded)?;
+
+    let challenge_bytes: [u8; CHALLENGE_LEN] = challenge.try_into()
+        .map_err(|_| SolutionError::MalformedInput("Challenge contains more bytes than originally sent"))?;
+
+    if !blacklist.try_insert(challenge_bytes).await {
+        return Err(SolutionError::Blacklisted);
+    }
+
+    let mut client_integrity_buf = [0u8; 32];
+    let client_integrity = STANDARD_NO_PAD.decode(integrity_base64, Out::from_slice(&mut client_integrity_buf))
+        .map_err(|_| SolutionError::MalformedInput("Base64 validation failed"))?;
+
+    if !check_renewal_integrity(challenge, difficulty_bits, expires_at, client_id, client_integrity) {
+        return Err(SolutionError::MalformedInput("Integrity check failed"));
+    }
+
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(e) => {
+            error!(error = ?e, "System time is before UNIX_EPOCH");
+            return Err(SolutionError::InternalError);
+        }
+    };
+
+    if expires_at < now {
+        return Err(SolutionError::TimedOut);
+    }
+
+    if !validate_challenge(nonce, challenge, difficulty_bits, expires_at) {
+        return Err(SolutionError::ValidationFailed);
+    }
+
+    Ok(SessionEntry { difficulty_bits: current.difficulty_bits.min(difficulty_bits), ..current })
+}
+
+/// Difficulty of a renewal challenge: the current difficulty minus the configured discount.
+#[inline]
+fn renewal_bits(current_bits: u8) -> u8 {
+    current_bits
+        .saturating_sub(CONFIG.renewal.bits_discount)
+        .max(CONFIG.renewal.min_bits)
+}
@@ -0,0 +1,126 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use crate::config::CONFIG;
+
+use actix_web::{HttpResponse, body::{BodySize, MessageBody}, http::StatusCode, web::Bytes};
+use tokio::time::{sleep, Duration, Instant, Sleep};
+#[cfg(feature = "debug")]
+use tracing::debug;
+
+/// Why a request is being rejected, each one can be tarpitted or not in `[tarpit.reasons]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarpitReason {
+    /// The circuit is on the ban list.
+    Banned,
+    /// The circuit fetched more challenges than its issuance bucket allows.
+    RateLimited,
+    /// The solution reused an already redeemed challenge.
+    Replayed,
+    /// The solution could not be parsed or its integrity check failed.
+    Malformed,
+    /// The solution does not meet the difficulty.
+    Failed,
+}
+
+impl TarpitReason {
+    #[inline]
+    fn enabled(self) -> bool {
+        let reasons = &CONFIG.tarpit.reasons;
+        match self {
+            TarpitReason::Banned => reasons.banned,
+            TarpitReason::RateLimited => reasons.rate_limited,
+            TarpitReason::Replayed => reasons.replayed,
+            TarpitReason::Malformed => reasons.malformed,
+            TarpitReason::Failed => reasons.failed,
+        }
+    }
+
+    #[inline]
+    fn status(self) -> StatusCode {
+        match self {
+            TarpitReason::Banned | TarpitReason::Replayed | TarpitReason::Malformed => StatusCode::FORBIDDEN,
+            TarpitReason::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            TarpitReason::Failed => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Holds rejected connections open, dripping one byte per interval, so the socket and Tor
+/// circuit of an abusive client stay busy instead of retrying right away.
+#[derive(Default)]
+pub struct Tarpit {
+    active: Arc<AtomicUsize>,
+}
+
+impl Tarpit {
+    /// Slow response for `reason`, or `None` if that reason is not tarpitted or
+    /// `max_connections` are already held, in which case the caller rejects normally.
+    #[must_use]
+    pub fn respond(&self, reason: TarpitReason) -> Option<HttpResponse> {
+        let cfg = &CONFIG.tarpit;
+        if !cfg.enabled || !reason.enabled() {
+            return None;
+        }
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < cfg.max_connections).then(|| active.saturating_add(1))
+            })
+            .ok()?;
+
+        #[cfg(feature = "debug")]
+        debug!(?reason, "Request tarpitted");
+
+        let interval_ms = cfg.interval_ms.max(1);
+        let body = TarpitBody {
+            remaining: cfg.duration.saturating_mul(1000).checked_div(interval_ms).unwrap_or(0),
+            interval: Duration::from_millis(interval_ms),
+            sleep: Box::pin(sleep(Duration::from_millis(interval_ms))),
+            _slot: TarpitSlot(self.active.clone()),
+        };
+        Some(HttpResponse::build(reason.status())
+            .content_type("text/plain")
+            .insert_header(("Cache-Control", "no-store"))
+            .body(body))
+    }
+}
+
+/// Releases a tarpit slot when the response ends or the client goes away.
+struct TarpitSlot(Arc<AtomicUsize>);
+
+impl Drop for TarpitSlot {
+    fn drop(&mut self) {
+        let _ = self.0.fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| Some(active.saturating_sub(1)));
+    }
+}
+
+struct TarpitBody {
+    remaining: u64,
+    interval: Duration,
+    sleep: Pin<Box<Sleep>>,
+    _slot: TarpitSlot,
+}
+
+impl MessageBody for TarpitBody {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        if self.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        if self.sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let next = Instant::now().checked_add(self.interval).unwrap_or_else(Instant::now);
+        self.sleep.as_mut().reset(next);
+        self.remaining = self.remaining.saturating_sub(1);
+        Poll::Ready(Some(Ok(Bytes::from_static(b" "))))
+    }
+}
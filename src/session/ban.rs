@@ -0,0 +1,258 @@
+use crate::identity::{ClientId, LimitKey};
+use crate::config::CONFIG;
+use tracing::warn;
+
+#[cfg(feature = "local")]
+use std::{hash::BuildHasherDefault, time::Duration};
+#[cfg(feature = "local")]
+use tokio::time::Instant;
+#[cfg(feature = "local")]
+use moka::{Expiry, future::Cache};
+#[cfg(feature = "local")]
+use twox_hash::XxHash3_64;
+
+#[cfg(feature = "redis")]
+use std::sync::LazyLock;
+#[cfg(feature = "redis")]
+use deadpool_redis::{redis::{cmd, Script}, Pool};
+#[cfg(feature = "redis")]
+use super::redis::POOL;
+#[cfg(feature = "redis")]
+use tracing::error;
+
+/// Counts a failure in the key at `KEYS[1]` and bans the circuit at `KEYS[2]` once it reaches
+/// `ARGV[2]` failures. The counter expires `ARGV[1]` seconds after the first failure and the
+/// ban after `ARGV[3]` seconds. Returns `1` if the circuit was banned.
+#[cfg(feature = "redis")]
+static FAILURE_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+local n = redis.call('INCR', KEYS[1])
+if n == 1 then redis.call('EXPIRE', KEYS[1], ARGV[1]) end
+if n < tonumber(ARGV[2]) then return 0 end
+redis.call('SET', KEYS[2], 1, 'EX', ARGV[3])
+redis.call('DEL', KEYS[1])
+return 1
+"));
+
+/// Expires the failure counter of a circuit a fixed `window` after its first failure, like the
+/// `EXPIRE` of the Redis backend, instead of sliding it with every failure.
+#[cfg(feature = "local")]
+struct FixedWindow(Duration);
+
+#[cfg(feature = "local")]
+impl Expiry<LimitKey, (u32, Instant)> for FixedWindow {
+    fn expire_after_create(&self, _key: &LimitKey, _value: &(u32, Instant), _created_at: std::time::Instant) -> Option<Duration> {
+        Some(self.0)
+    }
+
+    /// Keeps the deadline of the window, unless the counter restarted after it expired.
+    fn expire_after_update(&self, _key: &LimitKey, value: &(u32, Instant), _updated_at: std::time::Instant, duration_until_expiry: Option<Duration>) -> Option<Duration> {
+        if value.0 == 1 { Some(self.0) } else { duration_until_expiry }
+    }
+}
+
+/// Circuits temporarily refused after repeatedly failing validation.
+///
+/// With prefix aggregation, failures are also counted per prefix, which is banned as a whole
+/// once they reach its own `max_failures`.
+///
+/// Lives in Moka with the `local` feature and in Redis with the `redis` feature, so every
+/// instance sharing the Redis server shares the bans.
+pub struct BanList {
+    /// Failures of each circuit and the start of their window, which is also checked on every
+    /// failure, so the window holds on Tokio's clock and not only on Moka's expiry.
+    #[cfg(feature = "local")]
+    failures: Cache<LimitKey, (u32, Instant), BuildHasherDefault<XxHash3_64>>,
+    #[cfg(feature = "local")]
+    window: Duration,
+    #[cfg(feature = "local")]
+    bans: Cache<LimitKey, (), BuildHasherDefault<XxHash3_64>>,
+    #[cfg(feature = "redis")]
+    pool: &'static LazyLock<Pool>,
+}
+
+impl BanList {
+    #[cfg(feature = "local")]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_durations(Duration::from_secs(CONFIG.ban.window), Duration::from_secs(CONFIG.ban.duration))
+    }
+
+    #[cfg(feature = "local")]
+    fn with_durations(window: Duration, duration: Duration) -> Self {
+        Self {
+            failures:
+            Cache::builder()
+                .max_capacity(CONFIG.session.max_capacity)
+                .expire_after(FixedWindow(window))
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default()),
+            window,
+            bans:
+            Cache::builder()
+                .max_capacity(CONFIG.session.max_capacity)
+                .time_to_live(duration)
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default())
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    #[must_use]
+    pub fn new() -> Self {
+        Self { pool: &POOL }
+    }
+
+    /// Whether `client_id` is currently banned.
+    #[cfg(feature = "local")]
+    #[allow(clippy::unused_async)]
+    #[must_use]
+    pub async fn is_banned(&self, client_id: ClientId) -> bool {
+        CONFIG.ban.enabled && client_id.limit_keys().any(|key| self.bans.contains_key(&key))
+    }
+
+    /// Whether `client_id` is currently banned.
+    ///
+    /// Redis errors let the circuit through, the session lookup that follows still fails closed.
+    #[cfg(feature = "redis")]
+    #[must_use]
+    pub async fn is_banned(&self, client_id: ClientId) -> bool {
+        if !CONFIG.ban.enabled {
+            return false;
+        }
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return false;
+            },
+        };
+        let keys: Vec<String> = client_id.limit_keys().map(ban_key).collect();
+        match cmd("EXISTS").arg(keys).query_async::<u64>(&mut conn).await {
+            Ok(banned) => banned > 0,
+            Err(e) => {
+                error!(error = ?e, "Redis error.");
+                false
+            }
+        }
+    }
+
+    /// Counts a failed submission of `client_id`, and of its prefix if aggregated, banning
+    /// either once it reaches its `max_failures` within the window. Returns `true` if this
+    /// failure banned the circuit.
+    pub async fn failed(&self, client_id: ClientId) -> bool {
+        if !CONFIG.ban.enabled {
+            return false;
+        }
+        let mut banned = false;
+        for key in client_id.limit_keys() {
+            let max_failures = match key {
+                LimitKey::Client(_) => CONFIG.ban.max_failures,
+                LimitKey::Prefix(_) => CONFIG.identity.forwarded.aggregation.max_failures,
+            };
+            if self.count_failure(key, max_failures).await {
+                warn!(%key, duration = CONFIG.ban.duration, "Client banned after repeated failures");
+                banned = true;
+            }
+        }
+        banned
+    }
+
+    #[cfg(feature = "local")]
+    async fn count_failure(&self, key: LimitKey, max_failures: u32) -> bool {
+        let window = self.window;
+        let (failures, _) = self.failures
+            .entry(key)
+            .and_upsert_with(|existing| async move {
+                let now = Instant::now();
+                match existing.map(|e| e.into_value()) {
+                    Some((failures, started)) if now.duration_since(started) < window => (failures.saturating_add(1), started),
+                    _ => (1, now),
+                }
+            })
+            .await
+            .into_value();
+
+        if failures < max_failures {
+            return false;
+        }
+        self.failures.invalidate(&key).await;
+        self.bans.insert(key, ()).await;
+        true
+    }
+
+    #[cfg(feature = "redis")]
+    async fn count_failure(&self, key: LimitKey, max_failures: u32) -> bool {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return false;
+            },
+        };
+        match FAILURE_SCRIPT
+            .key(failures_key(key))
+            .key(ban_key(key))
+            .arg(CONFIG.ban.window)
+            .arg(max_failures)
+            .arg(CONFIG.ban.duration)
+            .invoke_async::<bool>(&mut conn)
+            .await {
+            Ok(banned) => banned,
+            Err(e) => {
+                error!(error = ?e, "Redis error.");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+#[inline]
+fn ban_key(key: LimitKey) -> String {
+    format!("ban:{key}")
+}
+
+#[cfg(feature = "redis")]
+#[inline]
+fn failures_key(key: LimitKey) -> String {
+    format!("fail:{key}")
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "local")]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn circuit_is_banned_at_max_failures(){
+        let bans = BanList::with_durations(Duration::from_secs(60), Duration::from_secs(60));
+        let key = LimitKey::Client(ClientId::from_ip([192, 0, 2, 1].into()));
+        let other = LimitKey::Client(ClientId::from_ip([192, 0, 2, 2].into()));
+
+        assert!(!bans.count_failure(key, 3).await);
+        assert!(!bans.count_failure(key, 3).await);
+        assert!(!bans.count_failure(other, 3).await, "Failures of other circuits should not add up");
+        assert!(!bans.bans.contains_key(&key));
+        assert!(bans.count_failure(key, 3).await, "The failure reaching the maximum should ban the circuit");
+        assert!(bans.bans.contains_key(&key));
+        assert!(!bans.bans.contains_key(&other));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failure_window_does_not_slide(){
+        let bans = BanList::with_durations(Duration::from_secs(300), Duration::from_secs(60));
+        let key = LimitKey::Client(ClientId::from_ip([192, 0, 2, 1].into()));
+
+        assert!(!bans.count_failure(key, 3).await);
+        tokio::time::advance(Duration::from_secs(200)).await;
+        assert!(!bans.count_failure(key, 3).await);
+        tokio::time::advance(Duration::from_secs(200)).await;
+        assert!(!bans.count_failure(key, 3).await, "Failures should be forgotten a window after the first one");
+        assert!(!bans.count_failure(key, 3).await);
+        assert!(bans.count_failure(key, 3).await, "A new window should start with the next failure");
+    }
+}
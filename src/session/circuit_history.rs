@@ -0,0 +1,197 @@
+use std::hash::BuildHasherDefault;
+use std::time::{Duration, Instant};
+use crate::identity::{ClientId, LimitKey};
+use crate::config::CONFIG;
+use moka::future::Cache;
+use twox_hash::XxHash3_64;
+#[cfg(feature = "debug")]
+use tracing::debug;
+
+/// Something a circuit did that affects its own difficulty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitEvent {
+    Fetched,
+    Failed,
+    Replayed,
+    Solved,
+}
+
+/// Decaying counters of what a circuit did recently.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitCounters {
+    pub fetched: f64,
+    pub failed: f64,
+    pub replays: f64,
+    pub sessions: f64,
+    updated_at: Instant,
+}
+
+impl CircuitCounters {
+    fn new(now: Instant) -> Self {
+        Self { fetched: 0.0, failed: 0.0, replays: 0.0, sessions: 0.0, updated_at: now }
+    }
+
+    /// Halves every counter once per `half_life` elapsed since the last update.
+    fn decay(&mut self, now: Instant, half_life: Duration) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        let factor = 0.5f64.powf(elapsed / half_life.as_secs_f64().max(f64::EPSILON));
+        self.fetched *= factor;
+        self.failed *= factor;
+        self.replays *= factor;
+        self.sessions *= factor;
+        self.updated_at = now;
+    }
+
+    fn apply(&mut self, event: CircuitEvent) {
+        match event {
+            CircuitEvent::Fetched => self.fetched += 1.0,
+            CircuitEvent::Failed => self.failed += 1.0,
+            CircuitEvent::Replayed => self.replays += 1.0,
+            CircuitEvent::Solved => self.sessions += 1.0,
+        }
+    }
+
+    /// Challenges fetched that never turned into a session.
+    #[must_use]
+    pub fn unsolved(&self) -> f64 {
+        (self.fetched - self.sessions).max(0.0)
+    }
+
+    /// Weighted score of suspicious behavior.
+    #[must_use]
+    pub fn penalty(&self) -> f64 {
+        let cfg = &CONFIG.pow.circuit_penalty;
+        self.unsolved() * cfg.unsolved_weight
+            + self.failed * cfg.failed_weight
+            + self.replays * cfg.replay_weight
+            + (self.sessions - cfg.free_sessions).max(0.0) * cfg.session_weight
+    }
+
+    /// Bits added to this circuit's difficulty, one per `points_per_bit` of penalty.
+    #[must_use]
+    pub fn extra_bits(&self) -> u8 {
+        self.extra_bits_at(CONFIG.pow.circuit_penalty.points_per_bit)
+    }
+
+    /// Bits added to the difficulty of every client of an aggregated prefix.
+    #[must_use]
+    pub fn prefix_extra_bits(&self) -> u8 {
+        self.extra_bits_at(CONFIG.identity.forwarded.aggregation.points_per_bit)
+    }
+
+    fn extra_bits_at(&self, points_per_bit: f64) -> u8 {
+        let penalty = self.penalty();
+        (0..=CONFIG.pow.circuit_penalty.max_extra_bits)
+            .take_while(|bits| f64::from(*bits) * points_per_bit <= penalty)
+            .last()
+            .unwrap_or(0)
+    }
+}
+
+/// Per-circuit behavior history used to raise the difficulty of abusive circuits.
+///
+/// With prefix aggregation, the prefix of every client keeps counters of its own.
+pub struct CircuitHistory {
+    inner: Cache<LimitKey, CircuitCounters, BuildHasherDefault<XxHash3_64>>,
+}
+
+impl CircuitHistory {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner:
+            Cache::builder()
+                .max_capacity(CONFIG.pow.circuit_penalty.max_capacity)
+                .time_to_idle(Duration::from_secs(CONFIG.pow.circuit_penalty.window))
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default())
+        }
+    }
+
+    /// Records `event` for `client_id`, and for its prefix if aggregated, returning the bits
+    /// to add to its difficulty: the higher of its own and its prefix's.
+    pub async fn record(&self, client_id: ClientId, event: CircuitEvent) -> u8 {
+        let mut extra_bits = 0;
+        for key in client_id.limit_keys() {
+            let counters = self.update(key, event).await;
+            extra_bits = extra_bits.max(match key {
+                LimitKey::Client(_) => counters.extra_bits(),
+                LimitKey::Prefix(_) => counters.prefix_extra_bits(),
+            });
+        }
+        extra_bits
+    }
+
+    async fn update(&self, key: LimitKey, event: CircuitEvent) -> CircuitCounters {
+        let half_life = Duration::from_secs(CONFIG.pow.circuit_penalty.half_life);
+        let counters = self.inner
+            .entry(key)
+            .and_upsert_with(|existing| async move {
+                let now = Instant::now();
+                let mut counters = existing.map_or_else(|| CircuitCounters::new(now), |e| e.into_value());
+                counters.decay(now, half_life);
+                counters.apply(event);
+                counters
+            })
+            .await
+            .into_value();
+
+        #[cfg(feature = "debug")]
+        debug!(%key, ?event, ?counters, "Circuit history updated");
+
+        counters
+    }
+}
+
+impl Default for CircuitHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn counters_halve_every_half_life(){
+        let half_life = Duration::from_secs(1200);
+        let now = Instant::now();
+        let Some(then) = now.checked_sub(half_life.saturating_mul(2)) else { return };
+
+        let mut counters = CircuitCounters::new(then);
+        counters.apply(CircuitEvent::Fetched);
+        counters.apply(CircuitEvent::Failed);
+        counters.apply(CircuitEvent::Failed);
+        counters.decay(now, half_life);
+        assert!((counters.fetched - 0.25).abs() < 1e-9, "Two half-lives should leave a quarter");
+        assert!((counters.failed - 0.5).abs() < 1e-9);
+        assert_eq!(counters.updated_at, now);
+
+        counters.decay(now, half_life);
+        assert!((counters.failed - 0.5).abs() < 1e-9, "No time elapsed should mean no decay");
+    }
+
+    #[test]
+    fn extra_bits_follow_the_penalty(){
+        let cfg = &CONFIG.pow.circuit_penalty;
+        let mut counters = CircuitCounters::new(Instant::now());
+        assert_eq!(counters.extra_bits_at(10.0), 0, "A clean circuit should not be penalized");
+
+        let mut last = 0;
+        for _ in 0..64 {
+            counters.apply(CircuitEvent::Failed);
+            let bits = counters.extra_bits_at(10.0);
+            assert!(bits >= last, "Extra bits should never drop as failures add up");
+            assert!(bits <= cfg.max_extra_bits);
+            if bits < cfg.max_extra_bits {
+                let penalty = counters.penalty();
+                assert!(f64::from(bits) * 10.0 <= penalty && penalty < f64::from(bits.saturating_add(1)) * 10.0,
+                    "One bit should be added per 10 points of penalty");
+            }
+            last = bits;
+        }
+        if cfg.failed_weight > 0.0 {
+            assert_eq!(last, cfg.max_extra_bits, "Extra bits should be capped at the maximum");
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+use crate::config::CONFIG;
+use super::effort_factor;
+
+#[cfg(feature = "local")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "redis")]
+use std::sync::LazyLock;
+#[cfg(feature = "redis")]
+use deadpool_redis::redis::Script;
+
+/// Spends one credit from every bucket whose theoretical arrival time is in `KEYS`, or from
+/// none if any of them is empty, like `spend_all` does locally. `ARGV[1]` is the current time, then
+/// `ARGV[2i]` and `ARGV[2i+1]` are the interval and burst of `KEYS[i]`, all in milliseconds.
+/// Returns `1` if the credits were spent.
+#[cfg(feature = "redis")]
+pub static SPEND_ALL_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+local now = tonumber(ARGV[1])
+local tats = {}
+for i, key in ipairs(KEYS) do
+  local tat = math.max(tonumber(redis.call('GET', key)) or now, now)
+  if tat - now > tonumber(ARGV[i * 2 + 1]) then return 0 end
+  tats[i] = tat + tonumber(ARGV[i * 2])
+end
+for i, key in ipairs(KEYS) do
+  redis.call('SET', key, tats[i], 'PX', tats[i] - now)
+end
+return 1
+"));
+
+/// Request credits of a session, as a token bucket in its generic cell rate algorithm form.
+///
+/// Instead of a token count and a refill timestamp, the bucket is a single "theoretical arrival
+/// time" (TAT) in milliseconds, so it can be updated with one compare-and-swap locally or one
+/// script in Redis. Every request pushes the TAT `interval_ms` forward; a request is refused
+/// when the TAT is more than `burst_ms` ahead of the current time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreditPolicy {
+    /// Milliseconds to regain one credit.
+    pub interval_ms: u64,
+    /// How far ahead of now the TAT may run, `capacity - 1` credits worth of intervals.
+    pub burst_ms: u64,
+}
+
+impl CreditPolicy {
+    /// Bucket of a session that solved `difficulty_bits`; capacity and refill rate are both
+    /// multiplied by the session effort factor.
+    #[must_use]
+    pub fn for_bits(difficulty_bits: u8) -> Self {
+        let factor = effort_factor(difficulty_bits);
+        Self::new(CONFIG.session.credits.capacity * factor, CONFIG.session.credits.refill_per_sec * factor)
+    }
+
+    /// Bucket shared by the sessions of an aggregated prefix, see `[identity.forwarded.aggregation]`.
+    #[must_use]
+    pub fn for_prefix() -> Self {
+        let cfg = &CONFIG.identity.forwarded.aggregation;
+        Self::new(cfg.credits_capacity, cfg.credits_refill_per_sec)
+    }
+
+    /// Bucket holding `capacity` credits that regains `refill_per_sec` credits per second.
+    #[must_use]
+    pub fn new(capacity: f64, refill_per_sec: f64) -> Self {
+        let rate = refill_per_sec.max(f64::EPSILON);
+        let capacity = capacity.max(1.0);
+        let millis = |secs: f64| Duration::try_from_secs_f64(secs)
+            .map_or(u64::MAX, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX));
+
+        Self {
+            interval_ms: millis(1.0 / rate).max(1),
+            burst_ms: millis((capacity - 1.0) / rate),
+        }
+    }
+
+    /// Spends one credit from a bucket at `tat`, returning the new TAT or `None` if it is empty.
+    #[inline]
+    #[must_use]
+    pub fn spend(&self, tat: u64, now_ms: u64) -> Option<u64> {
+        let tat = tat.max(now_ms);
+        if tat.saturating_sub(now_ms) > self.burst_ms {
+            return None;
+        }
+        Some(tat.saturating_add(self.interval_ms))
+    }
+}
+
+/// Spends one credit from every bucket, or from none if any of them is empty, so a bucket that
+/// refuses never drains the others.
+///
+/// Checking and spending are not one atomic step: a concurrent spend can empty a checked bucket,
+/// which then refuses after the ones before it were spent, at most one credit each.
+#[cfg(feature = "local")]
+#[must_use]
+pub fn spend_all(buckets: &[(&AtomicU64, CreditPolicy)], now_ms: u64) -> bool {
+    if !buckets.iter().all(|(tat, policy)| policy.spend(tat.load(Ordering::Acquire), now_ms).is_some()) {
+        return false;
+    }
+    buckets.iter().all(|(tat, policy)| {
+        tat.fetch_update(Ordering::AcqRel, Ordering::Acquire, |tat| policy.spend(tat, now_ms)).is_ok()
+    })
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn bucket_allows_capacity_then_refills(){
+        // Capacity of 3 credits, one credit back every second.
+        let policy = CreditPolicy { interval_ms: 1000, burst_ms: 2000 };
+        let now: u64 = 1_000_000;
+
+        let mut tat = now;
+        for _ in 0..3 {
+            tat = policy.spend(tat, now).unwrap_or(u64::MAX);
+        }
+        assert_eq!(tat, now.saturating_add(3000));
+        assert_eq!(policy.spend(tat, now), None, "The bucket should be empty after its capacity");
+        assert!(policy.spend(tat, now.saturating_add(1000)).is_some(), "A credit should come back after one interval");
+    }
+
+    #[test]
+    fn policy_from_capacity_and_rate(){
+        assert_eq!(CreditPolicy::new(3.0, 1.0), CreditPolicy { interval_ms: 1000, burst_ms: 2000 });
+        assert_eq!(CreditPolicy::new(0.0, 0.5).burst_ms, 0, "Capacity should never go below one credit");
+    }
+
+    #[cfg(feature = "local")]
+    #[test]
+    fn empty_bucket_drains_no_other(){
+        let policy = CreditPolicy { interval_ms: 1000, burst_ms: 2000 };
+        let now: u64 = 1_000_000;
+        let (client, prefix) = (AtomicU64::new(now), AtomicU64::new(now.saturating_add(3000)));
+
+        assert!(!spend_all(&[(&client, policy), (&prefix, policy)], now), "An empty prefix should refuse");
+        assert_eq!(client.load(Ordering::Acquire), now, "The client bucket should not be spent when the prefix refuses");
+        assert!(spend_all(&[(&client, policy)], now));
+        assert_eq!(client.load(Ordering::Acquire), now.saturating_add(1000));
+    }
+}
@@ -0,0 +1,219 @@
+use std::hash::BuildHasherDefault;
+use std::time::Duration;
+use crate::identity::{ClientId, LimitKey};
+use crate::config::CONFIG;
+use super::{credits::CreditPolicy, now_ms};
+use actix_web::web::Bytes;
+use moka::future::Cache;
+use twox_hash::XxHash3_64;
+
+#[cfg(feature = "local")]
+use std::sync::{Arc, atomic::AtomicU64};
+#[cfg(feature = "local")]
+use super::credits::spend_all;
+
+#[cfg(feature = "redis")]
+use std::sync::LazyLock;
+#[cfg(feature = "redis")]
+use deadpool_redis::Pool;
+#[cfg(feature = "redis")]
+use super::{credits::SPEND_ALL_SCRIPT, redis::POOL};
+#[cfg(feature = "redis")]
+use tracing::error;
+
+/// Rendered challenge page and the Unix timestamp it expires at.
+#[derive(Clone)]
+struct IssuedPage {
+    body: Bytes,
+    expires_at: u64,
+}
+
+/// Per-circuit limit on challenge pages, so fetching them can not be used to make the server
+/// spend randomness, MACs and template rendering without bound.
+///
+/// With prefix aggregation, every prefix also has a bucket of its own size shared by its clients.
+pub struct IssuanceLimiter {
+    policy: CreditPolicy,
+    prefix_policy: CreditPolicy,
+    #[cfg(feature = "local")]
+    buckets: Cache<LimitKey, Arc<AtomicU64>, BuildHasherDefault<XxHash3_64>>,
+    #[cfg(feature = "redis")]
+    pool: &'static LazyLock<Pool>,
+    /// Last page issued to each circuit, kept in process with either backend.
+    pages: Cache<ClientId, IssuedPage, BuildHasherDefault<XxHash3_64>>,
+}
+
+impl IssuanceLimiter {
+    #[must_use]
+    pub fn new() -> Self {
+        let cfg = &CONFIG.pow.issuance;
+        let policy = CreditPolicy::new(cfg.capacity, cfg.refill_per_sec);
+        let aggregation = &CONFIG.identity.forwarded.aggregation;
+        let prefix_policy = CreditPolicy::new(aggregation.issuance_capacity, aggregation.issuance_refill_per_sec);
+        Self {
+            policy,
+            prefix_policy,
+            #[cfg(feature = "local")]
+            buckets:
+            Cache::builder()
+                .max_capacity(CONFIG.session.max_capacity)
+                // An idle bucket refills completely by then, forgetting it changes nothing.
+                .time_to_idle(Duration::from_millis(
+                    policy.burst_ms.saturating_add(policy.interval_ms)
+                        .max(prefix_policy.burst_ms.saturating_add(prefix_policy.interval_ms))
+                ))
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default()),
+            #[cfg(feature = "redis")]
+            pool: &POOL,
+            pages:
+            Cache::builder()
+                .max_capacity(cfg.cache_capacity)
+                .time_to_live(Duration::from_secs(CONFIG.pow.max_challenge_ttl))
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default())
+        }
+    }
+
+    /// Spends one challenge of `client_id`, and of its prefix if aggregated, returning `false`
+    /// without spending either if either bucket is empty.
+    #[must_use]
+    pub async fn try_issue(&self, client_id: ClientId) -> bool {
+        if !CONFIG.pow.issuance.enabled {
+            return true;
+        }
+        let keys: Vec<LimitKey> = client_id.limit_keys().collect();
+        self.spend(&keys).await
+    }
+
+    #[inline]
+    fn policy(&self, key: LimitKey) -> CreditPolicy {
+        match key {
+            LimitKey::Client(_) => self.policy,
+            LimitKey::Prefix(_) => self.prefix_policy,
+        }
+    }
+
+    #[cfg(feature = "local")]
+    async fn spend(&self, keys: &[LimitKey]) -> bool {
+        let now = now_ms();
+        let mut tats = Vec::with_capacity(keys.len());
+        for &key in keys {
+            tats.push((self.buckets.get_with(key, async { Arc::new(AtomicU64::new(now)) }).await, self.policy(key)));
+        }
+        let buckets: Vec<(&AtomicU64, CreditPolicy)> = tats.iter().map(|(tat, policy)| (tat.as_ref(), *policy)).collect();
+        spend_all(&buckets, now)
+    }
+
+    /// Redis errors let the challenge through, issuing one is never unsafe.
+    #[cfg(feature = "redis")]
+    async fn spend(&self, keys: &[LimitKey]) -> bool {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return true;
+            },
+        };
+        let mut invocation = SPEND_ALL_SCRIPT.prepare_invoke();
+        invocation.arg(now_ms());
+        for &key in keys {
+            let policy = self.policy(key);
+            invocation.key(format!("issue:{key}")).arg(policy.interval_ms).arg(policy.burst_ms);
+        }
+        match invocation
+            .invoke_async::<bool>(&mut conn)
+            .await {
+            Ok(allowed) => allowed,
+            Err(e) => {
+                error!(error = ?e, "Redis error.");
+                true
+            }
+        }
+    }
+
+    /// Remembers the page issued to `client_id`, to serve it again once the circuit is limited.
+    pub async fn remember(&self, client_id: ClientId, body: Bytes, expires_at: u64) {
+        self.pages.insert(client_id, IssuedPage { body, expires_at }).await;
+    }
+
+    /// Last page issued to `client_id`, if it is still valid.
+    #[must_use]
+    pub async fn last_page(&self, client_id: ClientId) -> Option<Bytes> {
+        let now = now_ms() / 1000;
+        self.pages.get(&client_id).await
+            .filter(|page| page.expires_at > now)
+            .map(|page| page.body)
+    }
+
+    /// Seconds until a limited circuit regains one challenge, for `Retry-After`.
+    #[must_use]
+    pub fn retry_after(&self) -> u64 {
+        let interval_ms = if CONFIG.identity.forwarded.aggregation.enabled {
+            self.policy.interval_ms.max(self.prefix_policy.interval_ms)
+        } else {
+            self.policy.interval_ms
+        };
+        interval_ms.div_ceil(1000)
+    }
+}
+
+impl Default for IssuanceLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "local")]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::identity::Network;
+
+    #[tokio::test]
+    async fn bucket_limits_issuance(){
+        let limiter = IssuanceLimiter::new();
+        let key = LimitKey::Client(ClientId::from_ip([192, 0, 2, 1].into()));
+        let capacity = limiter.policy.burst_ms / limiter.policy.interval_ms + 1;
+
+        let mut issued = 0u64;
+        for _ in 0..capacity.saturating_add(5) {
+            if limiter.spend(&[key]).await {
+                issued = issued.saturating_add(1);
+            }
+        }
+        assert_eq!(issued, capacity, "A circuit should get its capacity of challenges and no more");
+        assert!(limiter.spend(&[LimitKey::Client(ClientId::UNIDENTIFIED)]).await, "Other circuits should keep their own bucket");
+    }
+
+    #[tokio::test]
+    async fn refused_prefix_does_not_drain_the_client(){
+        let limiter = IssuanceLimiter::new();
+        let client_id = ClientId::from_ip([192, 0, 2, 1].into());
+        let prefix = LimitKey::Prefix(Network { address: client_id.0 & !0xff, len: 120 });
+        let prefix_capacity = limiter.prefix_policy.burst_ms / limiter.prefix_policy.interval_ms + 1;
+        for _ in 0..prefix_capacity {
+            assert!(limiter.spend(&[prefix]).await);
+        }
+
+        assert!(!limiter.spend(&[LimitKey::Client(client_id), prefix]).await, "An empty prefix should refuse its clients");
+        let capacity = limiter.policy.burst_ms / limiter.policy.interval_ms + 1;
+        for _ in 0..capacity {
+            assert!(limiter.spend(&[LimitKey::Client(client_id)]).await, "Refused challenges should not be spent from the client");
+        }
+    }
+
+    #[tokio::test]
+    async fn last_page_is_reused_until_it_expires(){
+        let limiter = IssuanceLimiter::new();
+        let client_id = ClientId::from_ip([192, 0, 2, 1].into());
+        let now = now_ms() / 1000;
+
+        assert_eq!(limiter.last_page(client_id).await, None);
+        limiter.remember(client_id, Bytes::from_static(b"page"), now.saturating_add(60)).await;
+        assert_eq!(limiter.last_page(client_id).await, Some(Bytes::from_static(b"page")));
+        assert_eq!(limiter.last_page(ClientId::from_ip([192, 0, 2, 2].into())).await, None, "Pages should not be served to other circuits");
+
+        limiter.remember(client_id, Bytes::from_static(b"stale"), now).await;
+        assert_eq!(limiter.last_page(client_id).await, None, "Expired pages should not be served again");
+    }
+}
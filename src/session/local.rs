@@ -1,48 +1,184 @@
 #[cfg(feature = "local")]
 use std::{
     hash::BuildHasherDefault,
-    time::Duration
+    sync::{Arc, atomic::AtomicU64},
+    time::{Duration, Instant}
 };
 
 #[cfg(feature = "local")]
 use crate::{
+    identity::{ClientId, Network},
     config::CONFIG,
-    session::Session
+    session::{
+        Session,
+        SessionEntry,
+        credits::{CreditPolicy, spend_all},
+        now_ms,
+        session_ttl,
+        session_tti,
+        token::{SessionToken, max_lifetime}
+    }
 };
 
 #[cfg(feature = "local")]
-use moka::future::Cache;
+use moka::{Expiry, future::Cache, ops::compute::{CompResult, Op}};
 #[cfg(feature = "local")]
 use twox_hash::XxHash3_64;
+#[cfg(feature = "local")]
+use tracing::error;
 
 #[cfg(feature = "local")]
 pub struct MokaSession {
-    pub cache: Cache<u32, (), BuildHasherDefault<XxHash3_64>>,
+    pub cache: Cache<ClientId, LocalSession, BuildHasherDefault<XxHash3_64>>,
+    /// Theoretical arrival time of the credits shared by the sessions of each aggregated prefix.
+    prefixes: Cache<Network, Arc<AtomicU64>, BuildHasherDefault<XxHash3_64>>,
+    /// Theoretical arrival time of the credits of each session cookie, by token ID.
+    tokens: Cache<u128, Arc<AtomicU64>, BuildHasherDefault<XxHash3_64>>,
+}
+
+/// A session and the theoretical arrival time of its request credits.
+#[cfg(feature = "local")]
+#[derive(Clone)]
+pub struct LocalSession {
+    pub entry: SessionEntry,
+    credits: Arc<AtomicU64>,
+}
+
+/// Per-entry expiration scaled by the difficulty each session solved.
+///
+/// Reads extend the session by its time to idle, but never past its time to live.
+#[cfg(feature = "local")]
+struct EffortExpiry;
+
+#[cfg(feature = "local")]
+impl Expiry<ClientId, LocalSession> for EffortExpiry {
+    fn expire_after_create(&self, _client_id: &ClientId, session: &LocalSession, _created_at: Instant) -> Option<Duration> {
+        let bits = session.entry.difficulty_bits;
+        Some(session_tti(bits).min(session_ttl(bits)))
+    }
+
+    fn expire_after_read(
+        &self,
+        _client_id: &ClientId,
+        session: &LocalSession,
+        read_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+        last_modified_at: Instant,
+    ) -> Option<Duration> {
+        let bits = session.entry.difficulty_bits;
+        let remaining_ttl = session_ttl(bits).saturating_sub(read_at.saturating_duration_since(last_modified_at));
+        Some(session_tti(bits).min(remaining_ttl))
+    }
+
+    fn expire_after_update(
+        &self,
+        client_id: &ClientId,
+        session: &LocalSession,
+        updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.expire_after_create(client_id, session, updated_at)
+    }
 }
 
 #[cfg(feature = "local")]
 impl MokaSession {
     #[must_use]
     pub fn new() -> Self {
-        Self { cache:
-        Cache::builder()
-            .initial_capacity(CONFIG.session.initial_capacity)
-            .max_capacity(CONFIG.session.max_capacity)
-            .time_to_live(Duration::from_secs(CONFIG.session.ttl))
-            .time_to_idle(Duration::from_secs(CONFIG.session.tti))
-            .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default())
+        let prefix_policy = CreditPolicy::for_prefix();
+        Self {
+            cache:
+            Cache::builder()
+                .initial_capacity(CONFIG.session.initial_capacity)
+                .max_capacity(CONFIG.session.max_capacity)
+                .expire_after(EffortExpiry)
+                .support_invalidation_closures()
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default()),
+            prefixes:
+            Cache::builder()
+                .max_capacity(CONFIG.session.max_capacity)
+                // An idle bucket refills completely by then, forgetting it changes nothing.
+                .time_to_idle(Duration::from_millis(prefix_policy.burst_ms.saturating_add(prefix_policy.interval_ms)))
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default()),
+            tokens:
+            Cache::builder()
+                .max_capacity(CONFIG.session.max_capacity)
+                // No token outlives it, and the bucket of one that is still valid must not refill.
+                .time_to_live(Duration::from_secs(max_lifetime()))
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default())
+        }
+    }
+
+    /// Spends one credit of the session of `client_id`, and of `prefix` if it is aggregated,
+    /// returning its entry, or `None` without spending either if either bucket is empty.
+    async fn spend_in(&self, client_id: ClientId, prefix: Option<Network>) -> Option<SessionEntry> {
+        let session = self.cache.get(&client_id).await?;
+        if !CONFIG.session.credits.enabled {
+            return Some(session.entry);
         }
+        let policy = CreditPolicy::for_bits(session.entry.difficulty_bits);
+        let now = now_ms();
+        let spent = match prefix {
+            Some(prefix) => {
+                let shared = self.prefixes.get_with(prefix, async { Arc::new(AtomicU64::new(now)) }).await;
+                spend_all(&[(&session.credits, policy), (&shared, CreditPolicy::for_prefix())], now)
+            }
+            None => spend_all(&[(&session.credits, policy)], now),
+        };
+        spent.then_some(session.entry)
     }
 }
 
 #[cfg(feature = "local")]
 impl Session for MokaSession {
-    async fn contains(&self, circuit_id: u32) -> bool {
-        self.cache.contains_key(&circuit_id)
+    async fn get(&self, client_id: ClientId) -> Option<SessionEntry> {
+        // `get` rather than `contains_key`, which does not reset the idle timer.
+        self.cache.get(&client_id).await.map(|session| session.entry)
+    }
+
+    async fn set(&self, client_id: ClientId, entry: SessionEntry) {
+        let credits = Arc::new(AtomicU64::new(now_ms()));
+        self.cache.insert(client_id, LocalSession { entry, credits }).await;
+    }
+
+    async fn renew(&self, client_id: ClientId, entry: SessionEntry) -> bool {
+        let renewed = self.cache
+            .entry(client_id)
+            .and_compute_with(|current| std::future::ready(match current {
+                Some(current) => Op::Put(LocalSession { entry, credits: current.into_value().credits }),
+                None => Op::Nop,
+            }))
+            .await;
+        matches!(renewed, CompResult::ReplacedWith(_))
     }
 
-    async fn set(&self, circuit_id: u32) {
-        self.cache.insert(circuit_id, ()).await;
+    async fn spend(&self, client_id: ClientId) -> Option<SessionEntry> {
+        self.spend_in(client_id, client_id.prefix()).await
+    }
+
+    async fn spend_token(&self, token: &SessionToken) -> bool {
+        if !CONFIG.session.credits.enabled {
+            return true;
+        }
+        let now = now_ms();
+        let credits = self.tokens.get_with(token.id, async { Arc::new(AtomicU64::new(now)) }).await;
+        spend_all(&[(&credits, CreditPolicy::for_bits(token.entry.difficulty_bits))], now)
+    }
+
+    async fn remove(&self, client_id: ClientId) -> bool {
+        self.cache.remove(&client_id).await.is_some()
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn clear(&self) {
+        self.cache.invalidate_all();
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn remove_created_after(&self, timestamp: u64) {
+        if let Err(e) = self.cache.invalidate_entries_if(move |_, session| session.entry.created_at > timestamp) {
+            error!(error = ?e, "Failed to invalidate sessions");
+        }
     }
 }
 
@@ -51,4 +187,69 @@ impl Default for MokaSession {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "local")]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn sessions_of_a_prefix_share_its_credits(){
+        let session = MokaSession::new();
+        let prefix = Network { address: 0xffff_c000_0200, len: 120 };
+        let prefix_policy = CreditPolicy::for_prefix();
+        let prefix_capacity = prefix_policy.burst_ms / prefix_policy.interval_ms + 1;
+        let entry = SessionEntry { difficulty_bits: 20, created_at: 0 };
+
+        let mut spent = 0u64;
+        for n in 1..=u8::MAX {
+            let client_id = ClientId::from_ip([192, 0, 2, n].into());
+            session.set(client_id, entry).await;
+            while session.spend_in(client_id, Some(prefix)).await.is_some() {
+                spent = spent.saturating_add(1);
+            }
+            if spent >= prefix_capacity {
+                break;
+            }
+        }
+        assert_eq!(spent, prefix_capacity, "The sessions of a prefix should share its capacity");
+
+        let client_id = ClientId::from_ip([198, 51, 100, 1].into());
+        session.set(client_id, entry).await;
+        assert!(session.spend_in(client_id, Some(prefix)).await.is_none(), "New sessions of an empty prefix should be refused");
+        assert!(session.spend_in(client_id, None).await.is_some(), "A refused request should not spend the session's own credits");
+    }
+
+    #[tokio::test]
+    async fn cookie_sessions_spend_credits(){
+        let session = MokaSession::new();
+        let token = SessionToken { id: 1, entry: SessionEntry { difficulty_bits: 20, created_at: 0 }, expires_at: u64::MAX };
+        let policy = CreditPolicy::for_bits(token.entry.difficulty_bits);
+        for _ in 0..=policy.burst_ms / policy.interval_ms {
+            assert!(session.spend_token(&token).await);
+        }
+        assert!(!session.spend_token(&token).await, "A cookie should not authenticate past its credits");
+        assert!(session.spend_token(&SessionToken { id: 2, ..token }).await, "Other cookies should keep their own credits");
+    }
+
+    #[tokio::test]
+    async fn renewal_keeps_the_credits_left(){
+        let session = MokaSession::new();
+        let client_id = ClientId::from_ip([192, 0, 2, 1].into());
+        let entry = SessionEntry { difficulty_bits: 20, created_at: 0 };
+        assert!(!session.renew(client_id, entry).await, "Only existing sessions should be renewed");
+
+        session.set(client_id, entry).await;
+        let policy = CreditPolicy::for_bits(entry.difficulty_bits);
+        for _ in 0..=policy.burst_ms / policy.interval_ms {
+            assert!(session.spend_in(client_id, None).await.is_some());
+        }
+        assert!(session.spend_in(client_id, None).await.is_none());
+
+        let renewed = SessionEntry { difficulty_bits: 18, ..entry };
+        assert!(session.renew(client_id, renewed).await);
+        assert_eq!(session.get(client_id).await, Some(renewed));
+        assert!(session.spend_in(client_id, None).await.is_none(), "Renewing should not refill the credits");
+    }
+}
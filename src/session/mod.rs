@@ -1,4 +1,9 @@
+pub mod ban;
 pub mod challenge_blacklist;
+pub mod circuit_history;
+pub mod credits;
+pub mod issuance;
+pub mod token;
 
 
 #[cfg(feature = "local")]
@@ -13,8 +18,176 @@ mod redis;
 pub use redis::RedisSession as SessionCache;
 
 use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::identity::ClientId;
+use token::SessionToken;
+use crate::config::{CONFIG, IdentityMode};
+
+/// Set while the sessions of closed Tor circuits are removed as the circuits close.
+static CIRCUIT_EVENTS: AtomicBool = AtomicBool::new(false);
+
+/// Records whether the Tor circuit events are being received, see [`session_ttl`].
+pub fn set_circuit_events(received: bool) {
+    CIRCUIT_EVENTS.store(received, Ordering::Relaxed);
+}
+
+/// Whether sessions are keyed by Tor circuits whose closing is not being received, because the
+/// control port or its circuit events are disabled or the subscription is down.
+#[inline]
+fn circuit_events_lost() -> bool {
+    CONFIG.identity.mode == IdentityMode::Tor && !CIRCUIT_EVENTS.load(Ordering::Relaxed)
+}
 
 pub trait Session {
-    fn contains(&self, circuit_id: u32) -> impl Future<Output = bool>;
-    fn set(&self, circuit_id: u32) -> impl Future<Output = ()>;
+    fn get(&self, client_id: ClientId) -> impl Future<Output = Option<SessionEntry>>;
+    fn set(&self, client_id: ClientId, entry: SessionEntry) -> impl Future<Output = ()>;
+
+    /// Replaces the entry of an existing session and restarts its lifetime, keeping the request
+    /// credits it has left so a renewal does not refill them.
+    ///
+    /// Returns `false` if there is no session to renew.
+    fn renew(&self, client_id: ClientId, entry: SessionEntry) -> impl Future<Output = bool>;
+
+    /// Looks the session up and spends one of its request credits.
+    ///
+    /// Returns `None` if there is no session or its credits are exhausted.
+    fn spend(&self, client_id: ClientId) -> impl Future<Output = Option<SessionEntry>>;
+
+    /// Spends one request credit of the session carried by the cookie `token`, from a bucket
+    /// kept by its ID. Returns `false` if its credits are exhausted.
+    fn spend_token(&self, token: &SessionToken) -> impl Future<Output = bool>;
+
+    /// Removes the session of `client_id`, returning whether there was one.
+    fn remove(&self, client_id: ClientId) -> impl Future<Output = bool>;
+
+    /// Removes every session.
+    fn clear(&self) -> impl Future<Output = ()>;
+
+    /// Removes every session created after the Unix timestamp `timestamp`.
+    fn remove_created_after(&self, timestamp: u64) -> impl Future<Output = ()>;
+
+    fn contains(&self, client_id: ClientId) -> impl Future<Output = bool> {
+        async move { self.get(client_id).await.is_some() }
+    }
+}
+
+/// Data kept with every session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionEntry {
+    pub difficulty_bits: u8,
+    /// Unix timestamp at which the session was created.
+    pub created_at: u64,
+}
+
+impl SessionEntry {
+    /// Seconds since the session was created.
+    #[must_use]
+    pub fn age(&self, now: u64) -> u64 {
+        now.saturating_sub(self.created_at)
+    }
+
+    /// Serializes the entry as `<difficulty_bits>:<created_at>` for external stores.
+    #[must_use]
+    pub fn encode(&self) -> String {
+        format!("{}:{}", self.difficulty_bits, self.created_at)
+    }
+
+    #[must_use]
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        let colon = memchr::memchr(b':', value)?;
+        let (bits, created_at) = value.split_at(colon);
+        Some(Self {
+            difficulty_bits: atoi_simd::parse::<u8>(bits).ok()?,
+            created_at: atoi_simd::parse::<u64>(created_at.get(1..)?).ok()?,
+        })
+    }
+}
+
+/// Session time to live for a solve of `difficulty_bits`.
+///
+/// Capped at `fallback_max_ttl` while Tor circuits identify clients but their circuit events are
+/// not received, as sessions of closed circuits are then not removed before their ID is reused.
+#[must_use]
+pub fn session_ttl(difficulty_bits: u8) -> Duration {
+    let ttl = scale_lifetime(CONFIG.session.ttl, CONFIG.session.max_ttl, difficulty_bits);
+    if circuit_events_lost() {
+        return ttl.min(Duration::from_secs(CONFIG.tor.fallback_max_ttl));
+    }
+    ttl
 }
+
+/// Lifetime of a session cookie for a solve of `difficulty_bits`, scaled like [`session_ttl`].
+///
+/// Never capped: a cookie is not tied to the circuit it was issued on.
+#[must_use]
+pub fn cookie_ttl(difficulty_bits: u8) -> Duration {
+    scale_lifetime(CONFIG.session.ttl, CONFIG.session.max_ttl, difficulty_bits)
+}
+
+/// Session time to idle for a solve of `difficulty_bits`, capped like [`session_ttl`].
+#[must_use]
+pub fn session_tti(difficulty_bits: u8) -> Duration {
+    let tti = scale_lifetime(CONFIG.session.tti, CONFIG.session.max_tti, difficulty_bits);
+    if circuit_events_lost() {
+        return tti.min(Duration::from_secs(CONFIG.tor.fallback_max_tti));
+    }
+    tti
+}
+
+/// Multiplies `base` seconds by `effort_scale` for every bit solved above `effort_base_bits`,
+/// so clients that solved harder challenges during an attack keep their session longer.
+fn scale_lifetime(base: u64, max: u64, difficulty_bits: u8) -> Duration {
+    scaled(base, max, effort_factor(difficulty_bits))
+}
+
+/// `base` seconds multiplied by `factor`, capped at `max` (or `base` if it is higher).
+fn scaled(base: u64, max: u64, factor: f64) -> Duration {
+    let max = max.max(base);
+    let secs = (f64::from(u32::try_from(base).unwrap_or(u32::MAX)) * factor)
+        .min(f64::from(u32::try_from(max).unwrap_or(u32::MAX)));
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::from_secs(base))
+}
+
+/// `effort_scale` raised to the number of bits solved above `effort_base_bits`.
+fn effort_factor(difficulty_bits: u8) -> f64 {
+    factor(difficulty_bits, CONFIG.session.effort_base_bits, CONFIG.session.effort_scale)
+}
+
+/// `scale` raised to the number of bits solved above `base_bits`.
+fn factor(difficulty_bits: u8, base_bits: u8, scale: f64) -> f64 {
+    scale.max(1.0).powi(i32::from(difficulty_bits.saturating_sub(base_bits)))
+}
+
+/// Milliseconds since the Unix epoch, 0 if the system clock is before it.
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn entry_roundtrips_through_encoding(){
+        let entry = SessionEntry { difficulty_bits: 22, created_at: 17_57_30_33_29 };
+        assert_eq!(SessionEntry::decode(entry.encode().as_bytes()), Some(entry));
+        assert_eq!(SessionEntry::decode(b""), None, "Values without effort should not be accepted");
+    }
+
+    #[test]
+    fn lifetime_grows_with_effort_up_to_the_cap(){
+        let lifetime = |bits| scaled(300, 3600, factor(bits, 18, 1.5));
+        assert_eq!(lifetime(10), Duration::from_secs(300), "Solves below the base should get the base lifetime");
+        assert_eq!(lifetime(18), Duration::from_secs(300));
+        assert_eq!(lifetime(20), Duration::from_secs(675));
+        assert_eq!(lifetime(40), Duration::from_secs(3600), "Lifetimes should be capped at the maximum");
+
+        let lifetimes: Vec<Duration> = (0..=64).map(lifetime).collect();
+        assert!(lifetimes.windows(2).all(|pair| pair.first() <= pair.last()), "Lifetime should never shrink as effort grows");
+
+        assert_eq!(scaled(300, 60, factor(30, 18, 1.5)), Duration::from_secs(300), "A maximum below the base should not shorten it");
+        assert!((factor(30, 18, 0.5) - 1.0).abs() < f64::EPSILON, "A scale below 1 should not shorten lifetimes");
+    }
+}
\ No newline at end of file
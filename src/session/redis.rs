@@ -1,8 +1,9 @@
 use std::sync::LazyLock;
 use crate::config::CONFIG;
-use super::Session;
+use crate::identity::ClientId;
+use super::{Session, SessionEntry, credits::{CreditPolicy, SPEND_ALL_SCRIPT}, now_ms, session_ttl, token::SessionToken};
 use deadpool_redis::{
-    redis::cmd,
+    redis::{cmd, pipe, Script},
     Config,
     Runtime,
     Pool
@@ -16,41 +17,143 @@ pub static POOL: LazyLock<Pool> = LazyLock::new(|| {
     cfg.create_pool(Some(Runtime::Tokio1)).unwrap()
 });
 
+/// Spends one credit of the session hash at `KEYS[1]` atomically and returns its entry.
+///
+/// The hash holds the entry (`e`), the credit interval (`i`) and burst (`b`) in milliseconds,
+/// and the theoretical arrival time (`t`), see [`CreditPolicy`]. `ARGV[1]` is the current time
+/// in milliseconds and `ARGV[2]` is `1` when credits are enforced. With aggregation, `KEYS[2]`
+/// holds the theoretical arrival time of the prefix, whose interval and burst are `ARGV[3]` and
+/// `ARGV[4]`; neither bucket is spent unless both have a credit.
+#[cfg(feature = "redis")]
+static SPEND_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+local s = redis.call('HMGET', KEYS[1], 'e', 'i', 'b', 't')
+if not s[1] then return false end
+if ARGV[2] ~= '1' then return s[1] end
+local now = tonumber(ARGV[1])
+local tat = math.max(tonumber(s[4]) or now, now)
+if tat - now > tonumber(s[3]) then return false end
+local shared
+if KEYS[2] then
+  shared = math.max(tonumber(redis.call('GET', KEYS[2])) or now, now)
+  if shared - now > tonumber(ARGV[4]) then return false end
+  shared = shared + tonumber(ARGV[3])
+  redis.call('SET', KEYS[2], shared, 'PX', shared - now)
+end
+redis.call('HSET', KEYS[1], 't', tat + tonumber(s[2]))
+return s[1]
+"));
+
+/// Replaces the entry (`ARGV[1]`), credit interval (`ARGV[2]`) and burst (`ARGV[3]`) of the
+/// session hash at `KEYS[1]` and restarts its `ARGV[4]` seconds lifetime, keeping its theoretical
+/// arrival time. Returns `1` if there was a session to renew.
+#[cfg(feature = "redis")]
+static RENEW_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+if redis.call('EXISTS', KEYS[1]) == 0 then return 0 end
+redis.call('HSET', KEYS[1], 'e', ARGV[1], 'i', ARGV[2], 'b', ARGV[3])
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return 1
+"));
+
 #[cfg(feature = "redis")]
 pub struct RedisSession {
     pub pool: &'static LazyLock<Pool>,
 }
 
+/// Keys requested per `SCAN` round when removing sessions in bulk.
+#[cfg(feature = "redis")]
+const SCAN_COUNT: u64 = 1000;
+/// Pattern of the session keys, so bulk removal never touches the other keys of the database.
+#[cfg(feature = "redis")]
+const SESSION_PATTERN: &str = "session:*";
+
 #[cfg(feature = "redis")]
 impl RedisSession {
     #[must_use]
     pub fn new() -> Self {
         Self { pool: &POOL }
     }
+
+    /// Deletes every session whose entry matches `predicate`, scanning the session hashes in batches.
+    async fn remove_where(&self, predicate: impl Fn(&SessionEntry) -> bool) {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return;
+            },
+        };
+
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, keys) = match cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH").arg(SESSION_PATTERN)
+                .arg("TYPE").arg("hash")
+                .arg("COUNT").arg(SCAN_COUNT)
+                .query_async::<(u64, Vec<String>)>(&mut conn)
+                .await {
+                Ok(page) => page,
+                Err(e) => {
+                    error!(error = ?e, "Redis error.");
+                    return;
+                }
+            };
+
+            if !keys.is_empty() {
+                let mut entries = pipe();
+                for key in &keys {
+                    entries.cmd("HGET").arg(key).arg("e");
+                }
+                let entries = match entries.query_async::<Vec<Option<Vec<u8>>>>(&mut conn).await {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        error!(error = ?e, "Redis error.");
+                        return;
+                    }
+                };
+
+                let doomed: Vec<String> = keys.into_iter()
+                    .zip(entries)
+                    .filter(|(_, entry)| entry.as_deref().and_then(SessionEntry::decode).is_some_and(|entry| predicate(&entry)))
+                    .map(|(key, _)| key)
+                    .collect();
+                if !doomed.is_empty()
+                    && let Err(e) = cmd("DEL").arg(&doomed).query_async::<()>(&mut conn).await
+                {
+                    error!(error = ?e, "Redis error.");
+                }
+            }
+
+            if next == 0 {
+                return;
+            }
+            cursor = next;
+        }
+    }
 }
 
 #[cfg(feature = "redis")]
 impl Session for RedisSession {
     #[allow(clippy::must_use_candidate)]
-    async fn contains(&self, circuit_id: u32) -> bool {
+    async fn get(&self, client_id: ClientId) -> Option<SessionEntry> {
         let mut conn = match self.pool.get().await {
             Ok(conn) => conn,
             Err(e) => {
                 error!(error = ?e, "Failed to get connection from pool, blocking access for safety.");
-                return false
+                return None
             },
         };
 
-        match cmd("EXISTS").arg(circuit_id).query_async::<i64>(&mut conn).await {
-            Ok(v) => v > 0,
+        match cmd("HGET").arg(session_key(client_id)).arg("e").query_async::<Option<Vec<u8>>>(&mut conn).await {
+            Ok(v) => v.and_then(|value| SessionEntry::decode(&value)),
             Err(e) => {
                 error!(error = ?e, "Redis error, blocking access for safety.");
-                false
+                None
             }
         }
     }
 
-    async fn set(&self, circuit_id: u32) {
+    async fn set(&self, client_id: ClientId, entry: SessionEntry) {
         let mut conn = match self.pool.get().await {
             Ok(conn) => conn,
             Err(e) => {
@@ -59,11 +162,19 @@ impl Session for RedisSession {
             },
         };
 
-        match cmd("SET")
-            .arg(circuit_id)
-            .arg("")
-            .arg("EX")
-            .arg(CONFIG.session.ttl)
+        let key = session_key(client_id);
+        let policy = CreditPolicy::for_bits(entry.difficulty_bits);
+        match pipe()
+            .atomic()
+            .cmd("DEL").arg(&key).ignore()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("e").arg(entry.encode())
+            .arg("i").arg(policy.interval_ms)
+            .arg("b").arg(policy.burst_ms)
+            .arg("t").arg(now_ms())
+            .ignore()
+            .cmd("EXPIRE").arg(&key).arg(session_ttl(entry.difficulty_bits).as_secs()).ignore()
             .query_async::<()>(&mut conn)
             .await {
             Ok(()) => {}
@@ -73,6 +184,121 @@ impl Session for RedisSession {
         }
 
     }
+
+    async fn renew(&self, client_id: ClientId, entry: SessionEntry) -> bool {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return false;
+            },
+        };
+
+        let policy = CreditPolicy::for_bits(entry.difficulty_bits);
+        match RENEW_SCRIPT
+            .key(session_key(client_id))
+            .arg(entry.encode())
+            .arg(policy.interval_ms)
+            .arg(policy.burst_ms)
+            .arg(session_ttl(entry.difficulty_bits).as_secs())
+            .invoke_async::<bool>(&mut conn)
+            .await {
+            Ok(renewed) => renewed,
+            Err(e) => {
+                error!(error = ?e, "Redis error.");
+                false
+            }
+        }
+    }
+
+    async fn spend(&self, client_id: ClientId) -> Option<SessionEntry> {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool, blocking access for safety.");
+                return None
+            },
+        };
+
+        let mut invocation = SPEND_SCRIPT.prepare_invoke();
+        invocation
+            .key(session_key(client_id))
+            .arg(now_ms())
+            .arg(u8::from(CONFIG.session.credits.enabled));
+        if let Some(prefix) = client_id.prefix() {
+            let policy = CreditPolicy::for_prefix();
+            invocation.key(format!("credits:{prefix}")).arg(policy.interval_ms).arg(policy.burst_ms);
+        }
+        match invocation
+            .invoke_async::<Option<Vec<u8>>>(&mut conn)
+            .await {
+            Ok(v) => v.and_then(|value| SessionEntry::decode(&value)),
+            Err(e) => {
+                error!(error = ?e, "Redis error, blocking access for safety.");
+                None
+            }
+        }
+    }
+
+    async fn spend_token(&self, token: &SessionToken) -> bool {
+        if !CONFIG.session.credits.enabled {
+            return true;
+        }
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool, blocking access for safety.");
+                return false
+            },
+        };
+
+        let policy = CreditPolicy::for_bits(token.entry.difficulty_bits);
+        match SPEND_ALL_SCRIPT
+            .key(format!("credits:token:{:032x}", token.id))
+            .arg(now_ms())
+            .arg(policy.interval_ms)
+            .arg(policy.burst_ms)
+            .invoke_async::<bool>(&mut conn)
+            .await {
+            Ok(spent) => spent,
+            Err(e) => {
+                error!(error = ?e, "Redis error, blocking access for safety.");
+                false
+            }
+        }
+    }
+
+    async fn remove(&self, client_id: ClientId) -> bool {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return false;
+            },
+        };
+
+        match cmd("DEL").arg(session_key(client_id)).query_async::<u64>(&mut conn).await {
+            Ok(removed) => removed > 0,
+            Err(e) => {
+                error!(error = ?e, "Redis error.");
+                false
+            }
+        }
+    }
+
+    async fn clear(&self) {
+        self.remove_where(|_| true).await;
+    }
+
+    async fn remove_created_after(&self, timestamp: u64) {
+        self.remove_where(|entry| entry.created_at > timestamp).await;
+    }
+}
+
+#[cfg(feature = "redis")]
+#[inline]
+fn session_key(client_id: ClientId) -> String {
+    format!("session:{client_id}")
 }
 
 impl Default for RedisSession {
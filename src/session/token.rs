@@ -0,0 +1,348 @@
+use crate::config::{CONFIG, CookieSameSite};
+use crate::crypto::blake3::session_token_mac;
+use super::{SessionEntry, cookie_ttl};
+use actix_web::HttpRequest;
+use actix_web::cookie::{Cookie, SameSite, time::Duration as CookieDuration};
+use base64_simd::{URL_SAFE_NO_PAD, Out};
+use rand::Rng;
+use subtle::ConstantTimeEq;
+use tracing::error;
+
+#[cfg(feature = "local")]
+use std::{hash::BuildHasherDefault, sync::{Mutex, PoisonError}, time::Duration};
+#[cfg(feature = "local")]
+use moka::future::Cache;
+#[cfg(feature = "local")]
+use super::now_ms;
+#[cfg(feature = "local")]
+use twox_hash::XxHash3_64;
+
+#[cfg(feature = "redis")]
+use std::sync::LazyLock;
+#[cfg(feature = "redis")]
+use deadpool_redis::{redis::{cmd, pipe}, Pool};
+#[cfg(feature = "redis")]
+use super::redis::POOL;
+
+/// Token ID, creation time, expiry and difficulty bits.
+const PAYLOAD_LEN: usize = 16 + 8 + 8 + 1;
+/// Sorted set of the windows of creation times revoked in bulk, see [`TokenDenyList`].
+/// Members are `after:until`, scored by `until`.
+#[cfg(feature = "redis")]
+const REVOKED_WINDOWS_KEY: &str = "revoked:windows";
+
+/// Bytes of the MAC kept after the payload.
+const MAC_LEN: usize = 16;
+const TOKEN_LEN: usize = PAYLOAD_LEN + MAC_LEN;
+
+/// A session carried by the client in a cookie instead of being looked up by its identity.
+///
+/// The MAC makes it tamper-proof, so `auth` only has to check it and the deny list; the session
+/// then survives circuit changes. Its request credits are kept by its ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionToken {
+    /// Random ID, the handle to revoke the token with.
+    pub id: u128,
+    pub entry: SessionEntry,
+    /// Unix timestamp after which the token is refused.
+    pub expires_at: u64,
+}
+
+impl SessionToken {
+    /// New token for `entry`, expiring after the session ttl of its effort.
+    #[must_use]
+    pub fn issue(entry: SessionEntry) -> Self {
+        Self {
+            id: rand::rng().random(),
+            entry,
+            expires_at: entry.created_at.saturating_add(cookie_ttl(entry.difficulty_bits).as_secs()),
+        }
+    }
+
+    /// Token of the session cookie of `req`, if it carries a valid one that has not expired.
+    #[must_use]
+    pub fn from_request(req: &HttpRequest, now: u64) -> Option<Self> {
+        let cookie = req.cookie(&CONFIG.session.cookie.name)?;
+        Self::decode(cookie.value()).filter(|token| token.expires_at >= now)
+    }
+
+    fn payload(&self) -> [u8; PAYLOAD_LEN] {
+        let mut payload = [0u8; PAYLOAD_LEN];
+        payload[..16].copy_from_slice(&self.id.to_be_bytes());
+        payload[16..24].copy_from_slice(&self.entry.created_at.to_be_bytes());
+        payload[24..32].copy_from_slice(&self.expires_at.to_be_bytes());
+        payload[32] = self.entry.difficulty_bits;
+        payload
+    }
+
+    /// Serializes the token as base64url of the payload followed by its MAC.
+    #[must_use]
+    pub fn encode(&self) -> String {
+        let payload = self.payload();
+        let mut raw = [0u8; TOKEN_LEN];
+        raw[..PAYLOAD_LEN].copy_from_slice(&payload);
+        raw[PAYLOAD_LEN..].copy_from_slice(&session_token_mac(&payload)[..MAC_LEN]);
+        URL_SAFE_NO_PAD.encode_to_string(raw)
+    }
+
+    /// Parses a token, `None` if it is malformed or was not signed by this server.
+    #[must_use]
+    pub fn decode(value: &str) -> Option<Self> {
+        if value.len() != URL_SAFE_NO_PAD.encoded_length(TOKEN_LEN) {
+            return None;
+        }
+        let mut buf = [0u8; TOKEN_LEN];
+        let raw = URL_SAFE_NO_PAD.decode(value.as_bytes(), Out::from_slice(&mut buf)).ok()?;
+        let (payload, mac) = raw.split_first_chunk::<PAYLOAD_LEN>()?;
+        if !bool::from(mac.ct_eq(&session_token_mac(payload)[..MAC_LEN])) {
+            return None;
+        }
+        Some(Self {
+            id: u128::from_be_bytes(payload.get(..16)?.try_into().ok()?),
+            entry: SessionEntry {
+                difficulty_bits: *payload.get(32)?,
+                created_at: u64::from_be_bytes(payload.get(16..24)?.try_into().ok()?),
+            },
+            expires_at: u64::from_be_bytes(payload.get(24..32)?.try_into().ok()?),
+        })
+    }
+
+    /// `HttpOnly` cookie holding the token, expiring with it.
+    #[must_use]
+    pub fn cookie(&self, now: u64) -> Cookie<'static> {
+        let cfg = &CONFIG.session.cookie;
+        let max_age = i64::try_from(self.expires_at.saturating_sub(now)).unwrap_or(i64::MAX);
+        Cookie::build(cfg.name.clone(), self.encode())
+            .path("/")
+            .http_only(true)
+            .secure(cfg.secure)
+            .same_site(match cfg.same_site {
+                CookieSameSite::Strict => SameSite::Strict,
+                CookieSameSite::Lax => SameSite::Lax,
+            })
+            .max_age(CookieDuration::seconds(max_age))
+            .finish()
+    }
+
+    /// Token ID as 32 hex digits, the form the kill endpoint takes.
+    #[must_use]
+    pub fn id_hex(&self) -> String {
+        format!("{:032x}", self.id)
+    }
+}
+
+/// Revoked session tokens: single token IDs kept until the longest a token can live, and the
+/// windows of creation times whose tokens were revoked in bulk by the kill endpoint.
+///
+/// Each kill adds its own window, so tokens created between two kills stay valid and a later
+/// kill never brings tokens of an earlier one back. A window is dropped once every token
+/// created in it expired.
+///
+/// Lives in Moka with the `local` feature and in Redis with the `redis` feature.
+pub struct TokenDenyList {
+    #[cfg(feature = "local")]
+    denied: Cache<u128, (), BuildHasherDefault<XxHash3_64>>,
+    /// Tokens created after the first Unix timestamp and up to the second are revoked.
+    #[cfg(feature = "local")]
+    windows: Mutex<Vec<(u64, u64)>>,
+    #[cfg(feature = "redis")]
+    pool: &'static LazyLock<Pool>,
+}
+
+impl TokenDenyList {
+    #[cfg(feature = "local")]
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            denied:
+            Cache::builder()
+                .max_capacity(CONFIG.session.cookie.deny_capacity)
+                .time_to_live(Duration::from_secs(max_lifetime()))
+                .build_with_hasher(BuildHasherDefault::<XxHash3_64>::default()),
+            windows: Mutex::new(Vec::new()),
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    #[must_use]
+    pub fn new() -> Self {
+        Self { pool: &POOL }
+    }
+
+    /// Revokes the token `token_id`.
+    ///
+    /// Once `deny_capacity` IDs are kept, every token issued so far is revoked instead, as
+    /// letting Moka evict an ID would make its token valid again.
+    #[cfg(feature = "local")]
+    pub async fn deny(&self, token_id: u128) {
+        if self.denied.entry_count() >= CONFIG.session.cookie.deny_capacity {
+            error!(capacity = CONFIG.session.cookie.deny_capacity, "Token deny list is full, revoking every session cookie");
+            self.revoke_created_after(0, now_ms() / 1000).await;
+            self.denied.invalidate_all();
+        }
+        self.denied.insert(token_id, ()).await;
+    }
+
+    /// Revokes the token `token_id`.
+    #[cfg(feature = "redis")]
+    pub async fn deny(&self, token_id: u128) {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return;
+            },
+        };
+        if let Err(e) = cmd("SET").arg(deny_key(token_id)).arg(1).arg("EX").arg(max_lifetime())
+            .query_async::<()>(&mut conn).await
+        {
+            error!(error = ?e, "Redis error.");
+        }
+    }
+
+    /// Revokes every token created after `timestamp` and up to `now`.
+    #[cfg(feature = "local")]
+    #[allow(clippy::unused_async)]
+    pub async fn revoke_created_after(&self, timestamp: u64, now: u64) {
+        let expired = now.saturating_sub(max_lifetime());
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        windows.retain(|&(_, until)| until >= expired);
+        let (mut after, mut until) = (timestamp, now);
+        // Overlapping windows are merged, so one is kept per disjoint range.
+        windows.retain(|&(other_after, other_until)| {
+            let overlaps = other_after <= until && after <= other_until;
+            if overlaps {
+                after = after.min(other_after);
+                until = until.max(other_until);
+            }
+            !overlaps
+        });
+        windows.push((after, until));
+    }
+
+    /// Revokes every token created after `timestamp` and up to `now`.
+    #[cfg(feature = "redis")]
+    pub async fn revoke_created_after(&self, timestamp: u64, now: u64) {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return;
+            },
+        };
+        if let Err(e) = pipe()
+            .atomic()
+            .cmd("ZADD").arg(REVOKED_WINDOWS_KEY).arg(now).arg(format!("{timestamp}:{now}")).ignore()
+            .cmd("ZREMRANGEBYSCORE").arg(REVOKED_WINDOWS_KEY).arg("-inf").arg(format!("({}", now.saturating_sub(max_lifetime()))).ignore()
+            .cmd("EXPIRE").arg(REVOKED_WINDOWS_KEY).arg(max_lifetime()).ignore()
+            .query_async::<()>(&mut conn)
+            .await
+        {
+            error!(error = ?e, "Redis error.");
+        }
+    }
+
+    /// Whether `token` was revoked, by its ID or by its creation time.
+    #[cfg(feature = "local")]
+    #[allow(clippy::unused_async)]
+    #[must_use]
+    pub async fn is_revoked(&self, token: &SessionToken) -> bool {
+        let created_at = token.entry.created_at;
+        self.windows.lock().unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|&(after, until)| after < created_at && created_at <= until)
+            || self.denied.contains_key(&token.id)
+    }
+
+    /// Whether `token` was revoked, by its ID or by its creation time.
+    ///
+    /// Redis errors count as revoked, so the request falls back to the session lookup.
+    #[cfg(feature = "redis")]
+    #[must_use]
+    pub async fn is_revoked(&self, token: &SessionToken) -> bool {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = ?e, "Failed to get connection from pool.");
+                return true;
+            },
+        };
+        let created_at = token.entry.created_at;
+        match pipe()
+            .cmd("EXISTS").arg(deny_key(token.id))
+            .cmd("ZRANGEBYSCORE").arg(REVOKED_WINDOWS_KEY).arg(created_at).arg("+inf")
+            .query_async::<(bool, Vec<String>)>(&mut conn)
+            .await {
+            Ok((denied, windows)) => {
+                denied || windows.iter().any(|window| {
+                    // A malformed window revokes its tokens, like a Redis error does.
+                    window.split_once(':')
+                        .and_then(|(after, _)| after.parse::<u64>().ok())
+                        .is_none_or(|after| after < created_at)
+                })
+            }
+            Err(e) => {
+                error!(error = ?e, "Redis error.");
+                true
+            }
+        }
+    }
+}
+
+impl Default for TokenDenyList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Seconds the longest lived token lasts, `max_ttl` unless `ttl` is above it.
+#[inline]
+pub(super) fn max_lifetime() -> u64 {
+    CONFIG.session.max_ttl.max(CONFIG.session.ttl)
+}
+
+#[cfg(feature = "redis")]
+#[inline]
+fn deny_key(token_id: u128) -> String {
+    format!("deny:{token_id:032x}")
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn token_round_trip(){
+        let token = SessionToken {
+            id: 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef,
+            entry: SessionEntry { difficulty_bits: 20, created_at: 1_700_000_000 },
+            expires_at: 1_700_000_300,
+        };
+        let encoded = token.encode();
+        assert_eq!(SessionToken::decode(&encoded), Some(token));
+        assert_eq!(token.id_hex(), "0123456789abcdef0123456789abcdef");
+
+        let longer = SessionToken { expires_at: 1_800_000_000, ..token }.encode();
+        let forged = [encoded.get(..40).unwrap_or_default(), longer.get(40..).unwrap_or_default()].concat();
+        assert_eq!(SessionToken::decode(&forged), None, "A token with a changed expiry should not verify");
+        assert_eq!(SessionToken::decode("short"), None);
+    }
+
+    #[cfg(feature = "local")]
+    #[tokio::test]
+    async fn kills_revoke_disjoint_windows(){
+        let tokens = TokenDenyList::new();
+        let created = |created_at| SessionToken::issue(SessionEntry { difficulty_bits: 20, created_at });
+        tokens.revoke_created_after(1_000_000, 1_000_100).await;
+        tokens.revoke_created_after(1_000_200, 1_000_300).await;
+        assert!(tokens.is_revoked(&created(1_000_050)).await);
+        assert!(tokens.is_revoked(&created(1_000_250)).await);
+        assert!(!tokens.is_revoked(&created(1_000_150)).await, "Cookies created between two kills should stay valid");
+        assert!(!tokens.is_revoked(&created(1_000_000)).await);
+
+        tokens.revoke_created_after(1_000_050, 1_000_400).await;
+        assert!(tokens.is_revoked(&created(1_000_150)).await);
+        assert!(tokens.is_revoked(&created(1_000_350)).await);
+        assert!(!tokens.is_revoked(&created(1_000_450)).await);
+    }
+}
@@ -1,20 +1,107 @@
-use tokio::sync::watch::Sender;
-use tokio::time::{sleep, Duration};
-use crate::config::CONFIG;
-use tracing::error;
+use std::sync::Arc;
+use tokio::sync::watch::{Receiver, Sender};
+use tokio::time::{sleep, Duration, Instant};
+use crate::cgroup::{CgroupCpu, Psi};
+use crate::config::{CONFIG, CpuSource, DifficultyMode};
+use crate::difficulty::{Ewma, Ladder};
+use crate::load::{LoadMetrics, LoadSample};
+use crate::telemetry::{SolveTelemetry, bits_for_target};
+use crate::upstream::UpstreamStatus;
+use tracing::{error, info, warn};
 
-pub async fn cpu_usage(tx: Sender<f32>){
+/// Samples the CPU usage (host-wide or of our cgroup), pressure stall information, the
+/// upstream health and the request counters, combines them into a pressure score, smooths
+/// it and publishes the difficulty bits of the tier it falls into.
+///
+/// In `target_solve_time` mode the tier only provides a target median solve time, and
+/// the bits are derived from the hashrate recent clients achieved.
+pub async fn load_signal(
+    tx: Sender<u8>,
+    metrics: Arc<LoadMetrics>,
+    upstream: Receiver<UpstreamStatus>,
+    telemetry: Arc<SolveTelemetry>)
+{
     let mut sys = sysinfo::System::new();
+    let mut ewma = Ewma::new(CONFIG.pow.difficulty.smoothing);
+    let mut ladder = Ladder::new(&CONFIG.pow.difficulty.tiers, CONFIG.pow.difficulty.hysteresis);
+    let mut cgroup = match CONFIG.system.cpu_source {
+        CpuSource::Host => None,
+        CpuSource::Cgroup => {
+            let cgroup = CgroupCpu::new(&CONFIG.system.cgroup_path);
+            if cgroup.is_none() {
+                warn!("cgroup v2 CPU source unavailable, falling back to host CPU usage");
+            }
+            cgroup
+        }
+    };
+    let mut psi = if CONFIG.system.psi {
+        let psi = Psi::new(&CONFIG.system.cgroup_path);
+        if psi.is_none() {
+            warn!("cgroup v2 pressure stall information unavailable");
+        }
+        psi
+    } else {
+        None
+    };
+
     sys.refresh_cpu_usage();
     sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+    let mut last_sample = Instant::now();
+    let mut penalties = metrics.circuit_penalties();
     loop {
-        sys.refresh_cpu_usage();
-        let cpu = sys.global_cpu_usage();
-        match tx.send(cpu) {
+        let elapsed = last_sample.elapsed();
+        last_sample = Instant::now();
+
+        let (cpu, throttled) = match cgroup.as_mut().and_then(|c| c.sample(elapsed)) {
+            Some(s) => (s.cpu, s.throttled),
+            None => {
+                sys.refresh_cpu_usage();
+                (f64::from(sys.global_cpu_usage()), 0.0)
+            }
+        };
+        let stall = psi.as_mut().map(Psi::sample).unwrap_or_default();
+        let health = *upstream.borrow();
+        let sample = LoadSample {
+            throttled,
+            psi_cpu: stall.cpu,
+            psi_memory: stall.memory,
+            psi_io: stall.io,
+            upstream_latency: health.latency_ms,
+            upstream_errors: health.error_rate,
+            upstream_active: health.active,
+            ..metrics.sample(cpu, elapsed)
+        };
+        let pressure = ewma.update(sample.pressure(&CONFIG.system.load));
+        let mut bits = ladder.select(pressure);
+
+        let percentiles = telemetry.percentiles();
+        if CONFIG.pow.difficulty.mode == DifficultyMode::TargetSolveTime
+            && let Some(p) = percentiles.filter(|p| p.samples >= CONFIG.pow.telemetry.min_samples && p.hashrate_p50 > 0.0)
+            && let Some(target_ms) = ladder.target_solve_ms()
+        {
+            bits = bits_for_target(p.hashrate_p50, target_ms, CONFIG.pow.telemetry.min_bits, CONFIG.pow.telemetry.max_bits);
+        }
+
+        let total = metrics.circuit_penalties();
+        if total != penalties {
+            info!(
+                challenges = total.challenges.saturating_sub(penalties.challenges),
+                bits = total.bits.saturating_sub(penalties.bits),
+                total_challenges = total.challenges,
+                total_bits = total.bits,
+                "Issued challenges with a per-circuit penalty"
+            );
+            penalties = total;
+        }
+
+        #[cfg(feature = "debug")]
+        info!(?sample, ?percentiles, "Smoothed pressure at {pressure}, difficulty bits is {bits}");
+
+        match tx.send(bits) {
             Ok(()) => {},
             Err(e) => error!(error = ?e)
         }
         sleep(Duration::from_secs(CONFIG.system.cpu_usage_update_interval)).await;
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::f64::consts::LN_2;
+use std::sync::{Mutex, PoisonError};
+use crate::load::to_f64;
+
+/// One accepted solution.
+#[derive(Debug, Clone, Copy)]
+pub struct SolveSample {
+    pub difficulty_bits: u8,
+    /// Time between the challenge being issued and the solution being submitted.
+    pub server_ms: u64,
+}
+
+impl SolveSample {
+    /// Estimated hashes per second of the client: the expected `2^bits` hashes over the
+    /// server-side duration.
+    ///
+    /// The hashes and solve time the client reports are left out, any client could forge them
+    /// to move the median and with it the difficulty of everyone else. A single sample is off
+    /// by the luck of its solver, which the median over the window evens out.
+    #[must_use]
+    pub fn hashrate(&self) -> Option<f64> {
+        let expected = 1u64.checked_shl(u32::from(self.difficulty_bits)).unwrap_or(u64::MAX);
+        let rate = to_f64(expected) / (to_f64(self.server_ms.max(1)) / 1000.0);
+        (rate.is_finite() && rate > 0.0).then_some(rate)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SolvePercentiles {
+    pub samples: usize,
+    pub server_ms_p50: u64,
+    pub server_ms_p90: u64,
+    pub hashrate_p50: f64,
+}
+
+/// Rolling window of the most recent solves.
+pub struct SolveTelemetry {
+    samples: Mutex<VecDeque<SolveSample>>,
+    window: usize,
+}
+
+impl SolveTelemetry {
+    #[must_use]
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self { samples: Mutex::new(VecDeque::with_capacity(window)), window }
+    }
+
+    pub fn record(&self, sample: SolveSample) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        if samples.len() >= self.window {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    #[must_use]
+    pub fn percentiles(&self) -> Option<SolvePercentiles> {
+        let samples: Vec<SolveSample> = self.samples.lock().unwrap_or_else(PoisonError::into_inner).iter().copied().collect();
+        if samples.is_empty() {
+            return None;
+        }
+        let mut server_ms: Vec<u64> = samples.iter().map(|s| s.server_ms).collect();
+        server_ms.sort_unstable();
+        let mut hashrates: Vec<f64> = samples.iter().filter_map(SolveSample::hashrate).collect();
+        hashrates.sort_by(f64::total_cmp);
+
+        Some(SolvePercentiles {
+            samples: samples.len(),
+            server_ms_p50: percentile(&server_ms, 50).copied().unwrap_or(0),
+            server_ms_p90: percentile(&server_ms, 90).copied().unwrap_or(0),
+            hashrate_p50: percentile(&hashrates, 50).copied().unwrap_or(0.0),
+        })
+    }
+}
+
+/// Highest difficulty in `min_bits..=max_bits` whose median solve time, for a client
+/// hashing at `hashrate`, does not exceed `target_ms`.
+///
+/// The hashes needed for `n` trailing zero bits are geometrically distributed with a
+/// mean of `2^n`, so the median solve time is `ln 2 * 2^n / hashrate`.
+#[must_use]
+pub fn bits_for_target(hashrate: f64, target_ms: u64, min_bits: u8, max_bits: u8) -> u8 {
+    let target_secs = to_f64(target_ms) / 1000.0;
+    (min_bits..=max_bits)
+        .rev()
+        .find(|bits| LN_2 * 2f64.powi(i32::from(*bits)) / hashrate <= target_secs)
+        .unwrap_or(min_bits)
+}
+
+#[inline]
+fn percentile<T>(sorted: &[T], p: usize) -> Option<&T> {
+    let index = sorted.len().saturating_sub(1).saturating_mul(p) / 100;
+    sorted.get(index)
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn target_picks_highest_bits_within_budget(){
+        // 2^20 hashes/s solves 20 bits in a median of ~0.69s and 21 bits in ~1.39s.
+        let hashrate = 2f64.powi(20);
+        assert_eq!(bits_for_target(hashrate, 1000, 16, 28), 20);
+        assert_eq!(bits_for_target(hashrate, 1500, 16, 28), 21);
+        assert_eq!(bits_for_target(hashrate, 1, 16, 28), 16, "Should never go below the minimum");
+        assert_eq!(bits_for_target(hashrate, 1_000_000, 16, 24), 24, "Should never go above the maximum");
+    }
+
+    #[test]
+    fn hashrate_comes_from_server_timing(){
+        let sample = SolveSample { difficulty_bits: 10, server_ms: 1000 };
+        assert_eq!(sample.hashrate(), Some(1024.0));
+        assert_eq!(SolveSample { difficulty_bits: 20, server_ms: 2000 }.hashrate(), Some(2f64.powi(19)));
+        assert_eq!(SolveSample { difficulty_bits: 10, server_ms: 0 }.hashrate(), Some(1_024_000.0), "An instant solve should not divide by zero");
+    }
+
+    #[test]
+    fn window_keeps_recent_samples(){
+        let telemetry = SolveTelemetry::new(2);
+        for server_ms in [100, 200, 300] {
+            telemetry.record(SolveSample { difficulty_bits: 10, server_ms });
+        }
+        let percentiles = telemetry.percentiles();
+        assert!(percentiles.is_some_and(|p| p.samples == 2 && p.server_ms_p50 == 200 && p.server_ms_p90 == 200));
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt::Write as _;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration};
+use crate::identity::ClientId;
+use crate::config::{CONFIG, IdentityMode, TorAuth};
+use crate::session::{Session, SessionCache, set_circuit_events};
+use tracing::{error, info, warn};
+#[cfg(feature = "debug")]
+use tracing::debug;
+
+/// Status code of a successful control-port reply.
+const OK: u16 = 250;
+/// Status code of asynchronous event notifications.
+pub const EVENT: u16 = 650;
+
+trait ControlStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ControlStream for S {}
+
+/// How to authenticate to the control port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlAuth {
+    None,
+    /// Contents of the `control_auth_cookie` file.
+    Cookie(Vec<u8>),
+    Password(String),
+}
+
+impl ControlAuth {
+    /// Authentication from the `[tor]` configuration, reading the cookie file if needed.
+    ///
+    /// # Errors
+    /// Will return `Err` if the cookie file can not be read.
+    pub async fn from_config() -> io::Result<Self> {
+        Ok(match CONFIG.tor.auth {
+            TorAuth::None => ControlAuth::None,
+            TorAuth::Cookie => ControlAuth::Cookie(tokio::fs::read(&CONFIG.tor.cookie_path).await?),
+            TorAuth::Password => ControlAuth::Password(CONFIG.tor.password.clone()),
+        })
+    }
+
+    fn command(&self) -> String {
+        match self {
+            ControlAuth::None => "AUTHENTICATE".to_owned(),
+            ControlAuth::Cookie(cookie) => cookie.iter().fold("AUTHENTICATE ".to_owned(), |mut line, byte| {
+                let _ = write!(line, "{byte:02X}");
+                line
+            }),
+            ControlAuth::Password(password) => format!("AUTHENTICATE {}", quote(password)),
+        }
+    }
+}
+
+/// A reply of the control port: its status code and the text of every line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    /// Turns anything but `250 OK` into an error carrying the reply text.
+    ///
+    /// # Errors
+    /// Will return `Err` if the status code is not 250.
+    pub fn ok(self) -> io::Result<Self> {
+        if self.code == OK {
+            return Ok(self);
+        }
+        Err(io::Error::other(format!("Tor control port replied {} {}", self.code, self.lines.join(" "))))
+    }
+}
+
+/// An authenticated connection to the Tor control port, speaking its line-based text protocol.
+pub struct ControlConnection {
+    stream: BufStream<Box<dyn ControlStream>>,
+}
+
+impl ControlConnection {
+    /// Connects to `unix_socket` if it is not empty, to the TCP `address` otherwise, and authenticates.
+    ///
+    /// # Errors
+    /// Will return `Err` if the connection fails or the control port refuses the authentication.
+    pub async fn connect(address: &str, unix_socket: &str, auth: &ControlAuth) -> io::Result<Self> {
+        let stream: Box<dyn ControlStream> = if unix_socket.is_empty() {
+            Box::new(TcpStream::connect(address).await?)
+        } else {
+            Box::new(UnixStream::connect(unix_socket).await?)
+        };
+        let mut connection = Self { stream: BufStream::new(stream) };
+        connection.command(&auth.command()).await?.ok()?;
+        Ok(connection)
+    }
+
+    /// Sends one command line and reads its reply, skipping the asynchronous events before it.
+    ///
+    /// # Errors
+    /// Will return `Err` on I/O errors or a malformed reply, not on error status codes.
+    pub async fn command(&mut self, line: &str) -> io::Result<Reply> {
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        loop {
+            let reply = self.read_reply().await?;
+            if reply.code != EVENT {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Reads one reply, made of `code-text` lines and `code+text` data blocks ended by `.`,
+    /// up to its final `code text` line.
+    ///
+    /// # Errors
+    /// Will return `Err` on I/O errors, if the connection closes or if a line is malformed.
+    pub async fn read_reply(&mut self) -> io::Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed Tor control reply");
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok()).ok_or_else(invalid)?;
+            let text = line.get(4..).unwrap_or_default().to_owned();
+            match line.as_bytes().get(3) {
+                None | Some(b' ') => {
+                    lines.push(text);
+                    return Ok(Reply { code, lines });
+                }
+                Some(b'-') => lines.push(text),
+                Some(b'+') => {
+                    lines.push(text);
+                    loop {
+                        let data = self.read_line().await?;
+                        if data == "." {
+                            break;
+                        }
+                        lines.push(data);
+                    }
+                }
+                Some(_) => return Err(invalid()),
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Tor control port closed the connection"));
+        }
+        line.truncate(line.trim_end_matches(['\r', '\n']).len());
+        Ok(line)
+    }
+}
+
+/// Shared control-port client, connected on first use and reconnected after errors.
+#[derive(Default)]
+pub struct TorControl {
+    connection: Mutex<Option<ControlConnection>>,
+}
+
+impl TorControl {
+    /// Tears down the Tor circuit `circuit_id`.
+    ///
+    /// # Errors
+    /// Will return `Err` if the control port is disabled, unreachable, times out or refuses the command,
+    /// or if the circuit comes from another Tor daemon than the one behind the control port.
+    pub async fn close_circuit(&self, circuit_id: ClientId) -> io::Result<()> {
+        if !CONFIG.tor.enabled {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Tor control port is disabled"));
+        }
+        let Some(tor_circuit_id) = circuit_id.on_control_port() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Circuit is not from the Tor daemon of the control port"));
+        };
+        let reply = self.command(&format!("CLOSECIRCUIT {tor_circuit_id}")).await?;
+
+        #[cfg(feature = "debug")]
+        debug!(%circuit_id, ?reply, "CLOSECIRCUIT sent");
+
+        reply.ok().map(|_| ())
+    }
+
+    /// Closes `circuit_id` if circuits are closed on ban, logging instead of returning errors.
+    pub async fn close_banned(&self, circuit_id: ClientId) {
+        if CONFIG.identity.mode != IdentityMode::Tor || !CONFIG.tor.enabled || !CONFIG.tor.close_on_ban {
+            return;
+        }
+        match self.close_circuit(circuit_id).await {
+            Ok(()) => warn!(%circuit_id, "Banned circuit closed"),
+            Err(e) => error!(error = ?e, %circuit_id, "Failed to close banned circuit"),
+        }
+    }
+
+    async fn command(&self, line: &str) -> io::Result<Reply> {
+        let mut connection = self.connection.lock().await;
+        let result = timeout(Duration::from_millis(CONFIG.tor.timeout_ms), async {
+            if connection.is_none() {
+                let auth = ControlAuth::from_config().await?;
+                *connection = Some(ControlConnection::connect(&CONFIG.tor.address, &CONFIG.tor.unix_socket, &auth).await?);
+            }
+            match connection.as_mut() {
+                Some(connection) => connection.command(line).await,
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "Tor control port is not connected")),
+            }
+        }).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Tor control port timed out")));
+
+        if result.is_err() {
+            // The connection may be half-way through a reply, start over on the next command.
+            *connection = None;
+        }
+        result
+    }
+}
+
+/// Removes the session of every circuit Tor reports `CLOSED` or `FAILED`, as Tor reuses circuit
+/// IDs and the next circuit with the same ID would otherwise inherit the session.
+///
+/// Keeps its own control-port connection subscribed to `CIRC` events, reconnecting every
+/// `reconnect_interval` seconds while the control port is unavailable.
+pub async fn circuit_events(session: Arc<SessionCache>) {
+    loop {
+        match subscribe().await {
+            Ok(mut connection) => {
+                set_circuit_events(true);
+                info!("Subscribed to Tor circuit events");
+                loop {
+                    match connection.read_reply().await {
+                        Ok(reply) if reply.code == EVENT => {
+                            for circuit_id in reply.lines.iter().filter_map(|line| closed_circuit(line)) {
+                                let circuit_id = ClientId::from_control_port(circuit_id);
+                                session.remove(circuit_id).await;
+                                #[cfg(feature = "debug")]
+                                debug!(%circuit_id, "Circuit closed, its session was removed");
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!(error = ?e, "Lost the Tor circuit events, session lifetimes are shortened");
+                            break;
+                        }
+                    }
+                }
+                set_circuit_events(false);
+            }
+            Err(e) => error!(error = ?e, "Unable to subscribe to Tor circuit events"),
+        }
+        sleep(Duration::from_secs(CONFIG.tor.reconnect_interval.max(1))).await;
+    }
+}
+
+async fn subscribe() -> io::Result<ControlConnection> {
+    timeout(Duration::from_millis(CONFIG.tor.timeout_ms), async {
+        let auth = ControlAuth::from_config().await?;
+        let mut connection = ControlConnection::connect(&CONFIG.tor.address, &CONFIG.tor.unix_socket, &auth).await?;
+        connection.command("SETEVENTS CIRC").await?.ok()?;
+        Ok(connection)
+    }).await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Tor control port timed out")))
+}
+
+/// Circuit ID of a `CIRC <id> CLOSED|FAILED ...` event line.
+fn closed_circuit(line: &str) -> Option<u32> {
+    let mut fields = line.split(' ');
+    if fields.next()? != "CIRC" {
+        return None;
+    }
+    let circuit_id = fields.next()?.parse().ok()?;
+    matches!(fields.next()?, "CLOSED" | "FAILED").then_some(circuit_id)
+}
+
+/// Quotes `value` as a control-port `QuotedString`.
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len().saturating_add(2));
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn authenticate_commands_are_encoded(){
+        assert_eq!(ControlAuth::None.command(), "AUTHENTICATE");
+        assert_eq!(ControlAuth::Cookie(vec![0x00, 0xab, 0x10]).command(), "AUTHENTICATE 00AB10");
+        assert_eq!(ControlAuth::Password(r#"pa"ss\word"#.to_owned()).command(), r#"AUTHENTICATE "pa\"ss\\word""#);
+    }
+
+    #[test]
+    fn closed_circuit_events_are_parsed(){
+        assert_eq!(closed_circuit("CIRC 1000 CLOSED BUILD_FLAGS=IS_INTERNAL PURPOSE=HS_SERVICE_REND REASON=FINISHED"), Some(1000));
+        assert_eq!(closed_circuit("CIRC 7 FAILED REASON=TIMEOUT"), Some(7));
+        assert_eq!(closed_circuit("CIRC 7 BUILT"), None, "Only closed circuits should lose their session");
+        assert_eq!(closed_circuit("STREAM 7 CLOSED 0 1.2.3.4:80"), None);
+    }
+
+    #[tokio::test]
+    async fn closecircuit_against_fake_control_port(){
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap_or_else(|e| panic!("bind: {e}"));
+        let address = listener.local_addr().map(|a| a.to_string()).unwrap_or_default();
+
+        // Speaks just enough of the control protocol: password authentication and CLOSECIRCUIT,
+        // with an event and a multi-line reply mixed in.
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufStream::new(stream);
+                let mut line = String::new();
+                while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                    let reply = match line.trim_end() {
+                        r#"AUTHENTICATE "secret""# => "250 OK\r\n",
+                        "AUTHENTICATE" => "515 Authentication failed\r\n",
+                        "CLOSECIRCUIT 42" => "650 CIRC 7 BUILT\r\n250 OK\r\n",
+                        "GETINFO version" => "250-version=0.4.8.12\r\n250+config-text=\r\nSocksPort 0\r\n.\r\n250 OK\r\n",
+                        _ => "552 Unknown circuit \"7\"\r\n",
+                    };
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                    let _ = stream.flush().await;
+                    line.clear();
+                }
+            }
+        });
+
+        let refused = ControlConnection::connect(&address, "", &ControlAuth::None).await;
+        assert!(refused.is_err(), "Authentication without the password should be refused");
+
+        let connection = ControlConnection::connect(&address, "", &ControlAuth::Password("secret".to_owned())).await;
+        assert!(connection.is_ok(), "Authentication with the password should succeed");
+        let Ok(mut connection) = connection else { return };
+
+        let closed = connection.command("CLOSECIRCUIT 42").await;
+        assert!(closed.is_ok_and(|reply| reply.code == 250), "The event before the reply should be skipped");
+
+        let unknown = connection.command("CLOSECIRCUIT 7").await;
+        assert!(unknown.is_ok_and(|reply| reply.code == 552 && reply.ok().is_err()));
+
+        let info = connection.command("GETINFO version").await;
+        assert!(info.is_ok_and(|reply| reply.lines == ["version=0.4.8.12", "config-text=", "SocksPort 0", "OK"]));
+
+        drop(connection);
+        server.abort();
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::watch::Sender;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use crate::config::{CONFIG, ProbeMode};
+use crate::load::to_f64;
+use ada_url::Url;
+use tracing::{error, info, warn};
+
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// Last known state of the protected upstream, published by [`upstream_probe`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpstreamStatus {
+    /// Duration of the last probe in milliseconds, the timeout if it did not answer.
+    pub latency_ms: f64,
+    /// Ratio of failed probes (or dropped connections for `stub_status`) in the window.
+    pub error_rate: f64,
+    /// Active connections reported by nginx `stub_status`.
+    pub active: f64,
+    /// Set when the upstream failed enough consecutive probes to stop issuing new sessions.
+    pub lockdown: bool,
+}
+
+struct Probe {
+    status: u16,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct StubStatus {
+    active: u64,
+    accepts: u64,
+    handled: u64,
+}
+
+/// Periodically probes the configured upstream and publishes its health.
+pub async fn upstream_probe(tx: Sender<UpstreamStatus>) {
+    let cfg = &CONFIG.upstream;
+    let url = match Url::parse(&cfg.url, None) {
+        Ok(url) => url,
+        Err(e) => {
+            error!(error = ?e, url = %cfg.url, "Invalid upstream URL, health probe disabled");
+            return;
+        }
+    };
+    let Some(address) = plain_http_address(&url) else {
+        error!(url = %cfg.url, "Only http:// upstream URLs are supported, health probe disabled");
+        return;
+    };
+    let probe_timeout = Duration::from_millis(cfg.timeout_ms);
+    let mut window: VecDeque<bool> = VecDeque::with_capacity(cfg.window);
+    let mut consecutive_failures: u32 = 0;
+    let mut last_stub: Option<StubStatus> = None;
+    let mut was_healthy = true;
+    let mut was_locked_down = false;
+
+    loop {
+        let started = Instant::now();
+        let result = timeout(probe_timeout, probe(&url, &address, &cfg.unix_socket)).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "upstream probe timed out")));
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        let mut status = UpstreamStatus { latency_ms, ..UpstreamStatus::default() };
+        let healthy = match result {
+            Ok(probe) if (200..300).contains(&probe.status) => match cfg.mode {
+                ProbeMode::Health => true,
+                ProbeMode::StubStatus => match parse_stub_status(&probe.body) {
+                    Some(stub) => {
+                        status.active = to_f64(stub.active);
+                        if let Some(last) = last_stub {
+                            let accepts = stub.accepts.saturating_sub(last.accepts);
+                            let dropped = accepts.saturating_sub(stub.handled.saturating_sub(last.handled));
+                            if accepts > 0 {
+                                status.error_rate = to_f64(dropped) / to_f64(accepts);
+                            }
+                        }
+                        last_stub = Some(stub);
+                        true
+                    }
+                    None => {
+                        if was_healthy {
+                            warn!("Unable to parse nginx stub_status output");
+                        }
+                        false
+                    }
+                },
+            },
+            Ok(probe) => {
+                if was_healthy {
+                    warn!(status = probe.status, "Upstream health probe returned an error status");
+                }
+                false
+            }
+            Err(e) => {
+                if was_healthy {
+                    warn!(error = ?e, "Upstream health probe failed");
+                }
+                false
+            }
+        };
+        if healthy && !was_healthy {
+            info!(consecutive_failures, "Upstream health probe recovered");
+        }
+        was_healthy = healthy;
+
+        if window.len() >= cfg.window.max(1) {
+            window.pop_front();
+        }
+        window.push_back(healthy);
+        consecutive_failures = if healthy { 0 } else { consecutive_failures.saturating_add(1) };
+
+        let failures = window.iter().filter(|ok| !**ok).count();
+        let failure_rate = to_f64(u64::try_from(failures).unwrap_or(u64::MAX)) / to_f64(u64::try_from(window.len()).unwrap_or(u64::MAX));
+        status.error_rate = status.error_rate.max(failure_rate);
+        status.lockdown = cfg.lockdown && consecutive_failures >= cfg.lockdown_after;
+
+        if status.lockdown && !was_locked_down {
+            error!(consecutive_failures, "Upstream is failing, new sessions are locked down");
+        } else if !status.lockdown && was_locked_down {
+            warn!("Upstream recovered, new sessions are issued again");
+        }
+        was_locked_down = status.lockdown;
+
+        match tx.send(status) {
+            Ok(()) => {},
+            Err(e) => error!(error = ?e)
+        }
+        sleep(Duration::from_secs(cfg.interval)).await;
+    }
+}
+
+/// `host:port` to reach a plain `http://` URL at, `None` for any other scheme: there is no TLS
+/// client, so an `https://` upstream can not be spoken to.
+#[must_use]
+pub(crate) fn plain_http_address(url: &Url) -> Option<String> {
+    if url.protocol() != "http:" {
+        return None;
+    }
+    let port = if url.port().is_empty() { "80" } else { url.port() };
+    Some(format!("{}:{port}", url.hostname()))
+}
+
+async fn probe(url: &Url, address: &str, unix_socket: &str) -> io::Result<Probe> {
+    if unix_socket.is_empty() {
+        let stream = TcpStream::connect(address).await?;
+        request(stream, url).await
+    } else {
+        let stream = UnixStream::connect(unix_socket).await?;
+        request(stream, url).await
+    }
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, url: &Url) -> io::Result<Probe> {
+    // HTTP/1.0 keeps the response unchunked and closes the connection after it.
+    let head = format!(
+        "GET {}{} HTTP/1.0\r\nHost: {}\r\nUser-Agent: foxyon\r\nConnection: close\r\n\r\n",
+        url.pathname(), url.search(), url.host()
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_LEN).read_to_end(&mut response).await?;
+    parse_response(response)
+}
+
+fn parse_response(mut response: Vec<u8>) -> io::Result<Probe> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed upstream response");
+    let status = response
+        .split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| atoi_simd::parse::<u16>(code).ok())
+        .ok_or_else(invalid)?;
+    let body_start = memchr::memmem::find(&response, b"\r\n\r\n").ok_or_else(invalid)?.saturating_add(4);
+    let body = response.split_off(body_start);
+    Ok(Probe { status, body })
+}
+
+/// Parses nginx `stub_status` output:
+///
+/// ```text
+/// Active connections: 291
+/// server accepts handled requests
+///  16630948 16630948 31070465
+/// Reading: 6 Writing: 179 Waiting: 106
+/// ```
+fn parse_stub_status(body: &[u8]) -> Option<StubStatus> {
+    let body = std::str::from_utf8(body).ok()?;
+    let mut lines = body.lines();
+    let active = lines.next()?.strip_prefix("Active connections:")?.trim().parse().ok()?;
+    lines.next()?;
+    let mut counters = lines.next()?.split_whitespace().map(str::parse::<u64>);
+    let accepts = counters.next()?.ok()?;
+    let handled = counters.next()?.ok()?;
+    Some(StubStatus { active, accepts, handled })
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn only_plain_http_is_spoken(){
+        let address = |url: &str| Url::parse(url, None).ok().and_then(|url| plain_http_address(&url));
+        assert_eq!(address("http://127.0.0.1/health").as_deref(), Some("127.0.0.1:80"));
+        assert_eq!(address("http://[::1]:8080/health").as_deref(), Some("[::1]:8080"));
+        assert_eq!(address("https://example.com/health"), None, "https would be spoken to in plaintext");
+    }
+
+    #[test]
+    fn stub_status_is_parsed(){
+        let body = b"Active connections: 291 \nserver accepts handled requests\n 16630948 16630940 31070465 \nReading: 6 Writing: 179 Waiting: 106 \n";
+        assert_eq!(parse_stub_status(body), Some(StubStatus { active: 291, accepts: 16630948, handled: 16630940 }));
+        assert_eq!(parse_stub_status(b"<html>Not found</html>"), None);
+    }
+
+    #[test]
+    fn response_status_and_body_are_split(){
+        let probe = parse_response(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 2\r\n\r\nko".to_vec());
+        assert!(probe.is_ok_and(|p| p.status == 503 && p.body == b"ko"));
+        assert!(parse_response(b"garbage".to_vec()).is_err());
+    }
+}